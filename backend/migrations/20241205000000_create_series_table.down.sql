@@ -0,0 +1,5 @@
+ALTER TABLE matches DROP COLUMN IF EXISTS series_id;
+
+DROP TRIGGER IF EXISTS update_series_updated_at ON series;
+
+DROP TABLE IF EXISTS series;
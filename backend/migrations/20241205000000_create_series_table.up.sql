@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS series (
+    id UUID PRIMARY KEY NOT NULL,
+    state status NOT NULL DEFAULT 'pending',
+    target_wins INTEGER NOT NULL,
+    x_wins INTEGER NOT NULL DEFAULT 0,
+    o_wins INTEGER NOT NULL DEFAULT 0,
+    ties INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TRIGGER update_series_updated_at
+BEFORE UPDATE ON series
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
+
+ALTER TABLE matches ADD COLUMN IF NOT EXISTS series_id UUID REFERENCES series(id);
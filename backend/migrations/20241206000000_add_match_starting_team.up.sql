@@ -0,0 +1 @@
+ALTER TABLE matches ADD COLUMN IF NOT EXISTS starting_team status NOT NULL DEFAULT 'x';
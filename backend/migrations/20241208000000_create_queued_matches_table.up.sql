@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS queued_matches (
+    id UUID PRIMARY KEY NOT NULL,
+    position INTEGER NOT NULL,
+    settings JSONB NOT NULL DEFAULT '{}',
+    best_of INTEGER,
+    starts_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
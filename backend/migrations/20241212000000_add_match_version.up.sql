@@ -0,0 +1,2 @@
+-- Incremented on every board update so concurrent writers can't overwrite each other
+ALTER TABLE matches ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 0;
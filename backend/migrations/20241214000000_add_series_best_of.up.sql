@@ -0,0 +1,4 @@
+-- Series with ties end once every game has been played, so the length is kept alongside the target
+ALTER TABLE series ADD COLUMN IF NOT EXISTS best_of INTEGER;
+UPDATE series SET best_of = target_wins * 2 - 1 WHERE best_of IS NULL;
+ALTER TABLE series ALTER COLUMN best_of SET NOT NULL;
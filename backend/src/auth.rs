@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+
+use crate::AppState;
+
+// The holder of the API key used for a request, available to admin handlers as an extension
+#[derive(Clone, Debug)]
+pub struct Admin {
+    pub name: String,
+}
+
+// Admin routes need `Authorization: Bearer <key>` with one of the configured API keys
+pub async fn require_admin(
+    State(data): State<Arc<AppState>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let admin = auth.and_then(|TypedHeader(auth)| {
+        data.config
+            .admin_keys
+            .iter()
+            .find(|admin_key| keys_match(&admin_key.key, auth.token()))
+    });
+
+    let Some(admin_key) = admin else {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid API key").into_response();
+    };
+
+    request.extensions_mut().insert(Admin {
+        name: admin_key.name.clone(),
+    });
+    next.run(request).await
+}
+
+// Looks at every byte so the time taken doesn't reveal how much of a key matched
+fn keys_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -0,0 +1,73 @@
+use std::str::FromStr;
+
+use crate::schema::MatchTiming;
+
+// Server settings read from the environment
+pub struct Config {
+    // Used for matches created without their own timing
+    pub timing: MatchTiming,
+    // Bearer tokens accepted by the admin API. The admin API is closed when empty.
+    pub admin_keys: Vec<AdminKey>,
+}
+
+// `ADMIN_API_KEYS` is a comma separated list of `name:key` pairs. The name is
+// recorded in the audit log, keys without one are logged as `admin`.
+pub struct AdminKey {
+    pub name: String,
+    pub key: String,
+}
+
+impl AdminKey {
+    fn parse(entry: &str) -> Option<Self> {
+        let (name, key) = match entry.trim().split_once(':') {
+            Some((name, key)) => (name.trim(), key.trim()),
+            None => ("admin", entry.trim()),
+        };
+
+        (!key.is_empty()).then(|| Self {
+            name: name.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = MatchTiming::default();
+
+        let admin_keys: Vec<AdminKey> = std::env::var("ADMIN_API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(AdminKey::parse)
+            .collect();
+        if admin_keys.is_empty() {
+            tracing::warn!("ADMIN_API_KEYS is not set, the admin API is disabled");
+        }
+
+        let timing = MatchTiming {
+            turn_secs: env_or("TURN_SECS", defaults.turn_secs),
+            intermission_secs: env_or("INTERMISSION_SECS", defaults.intermission_secs),
+            overtime_poll_secs: env_or("OVERTIME_POLL_SECS", defaults.overtime_poll_secs),
+            min_players_per_team: env_or("MIN_PLAYERS_PER_TEAM", defaults.min_players_per_team),
+        };
+        let timing = match timing.validate() {
+            Ok(()) => timing,
+            Err(e) => {
+                tracing::warn!("Invalid timing, using the defaults: {}", e);
+                defaults
+            }
+        };
+
+        Self { timing, admin_keys }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!("Invalid value for {}, using the default", key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -3,8 +3,12 @@ use sqlx::{query_as, Pool, Postgres};
 use uuid::Uuid;
 
 use crate::{
-    model::MatchModel,
-    schema::{Board, Status},
+    error::VersionConflict,
+    model::{AuditEntryModel, MatchModel, QueuedMatchModel, SeriesModel},
+    schema::{
+        AuditQuery, Board, CreateAuditEntrySchema, CreateQueuedMatchSchema, MatchSettings,
+        SeriesSchema, Status,
+    },
 };
 
 pub async fn crud_get_matches(
@@ -26,7 +30,7 @@ pub async fn crud_get_matches(
 pub async fn crud_get_latest_match(db: &Pool<Postgres>) -> Result<MatchModel, anyhow::Error> {
     let match_model: MatchModel = sqlx::query_as(
         r#"
-        SELECT id, state, board, created_at, updated_at
+        SELECT id, state, board, settings, series_id, starting_team, version, created_at, updated_at
         FROM matches
         ORDER BY created_at DESC
         LIMIT 1
@@ -42,7 +46,7 @@ pub async fn crud_get_latest_match(db: &Pool<Postgres>) -> Result<MatchModel, an
 pub async fn crud_get_match(db: &Pool<Postgres>, id: Uuid) -> Result<MatchModel, anyhow::Error> {
     let match_model: MatchModel = sqlx::query_as(
         r#"
-        SELECT id, state, board, created_at, updated_at
+        SELECT id, state, board, settings, series_id, starting_team, version, created_at, updated_at
         FROM matches
         WHERE id = $1
         "#,
@@ -55,21 +59,30 @@ pub async fn crud_get_match(db: &Pool<Postgres>, id: Uuid) -> Result<MatchModel,
     Ok(match_model)
 }
 
-pub async fn crud_create_match(db: &Pool<Postgres>) -> Result<MatchModel> {
+pub async fn crud_create_match(
+    db: &Pool<Postgres>,
+    board: Board,
+    settings: MatchSettings,
+    series_id: Option<Uuid>,
+) -> Result<MatchModel> {
     let state = Status::Pending;
-    let board_json = serde_json::to_value(&Board::new())?;
+    let board_json = serde_json::to_value(board)?;
+    let settings_json = serde_json::to_value(settings)?;
     let id = Uuid::new_v4();
 
     let m: MatchModel = query_as(
         r#"
-        INSERT INTO matches (id, state, board) 
-        VALUES ($1, $2, $3)
+        INSERT INTO matches (id, state, board, settings, series_id, starting_team) 
+        VALUES ($1, $2, $3, $4, $5, $6)
         RETURNING *
         "#,
     )
     .bind(id)
     .bind(state)
     .bind(board_json)
+    .bind(settings_json)
+    .bind(series_id)
+    .bind(Status::from(board.current_team))
     .fetch_one(db)
     .await
     .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
@@ -77,21 +90,269 @@ pub async fn crud_create_match(db: &Pool<Postgres>) -> Result<MatchModel> {
     Ok(m)
 }
 
+// Only succeeds if the match is still at `version`, otherwise fails with `VersionConflict`
 pub async fn crud_update_match(
     db: &Pool<Postgres>,
     id: Uuid,
+    version: u32,
     state: Status,
     board: Board,
 ) -> Result<MatchModel, anyhow::Error> {
-    let board_json = serde_json::to_value(&board)?;
+    let board_json = serde_json::to_value(board)?;
+    let m: Option<MatchModel> = sqlx::query_as(
+        r#"
+        UPDATE matches SET (state, board, version) = ($3, $4, version + 1)
+        WHERE id = $1 AND version = $2
+        RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(version as i32)
+    .bind(state)
+    .bind(board_json)
+    .fetch_optional(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    m.ok_or_else(|| {
+        VersionConflict {
+            match_id: id,
+            version,
+        }
+        .into()
+    })
+}
+
+pub async fn crud_update_match_settings(
+    db: &Pool<Postgres>,
+    id: Uuid,
+    settings: &MatchSettings,
+) -> Result<MatchModel, anyhow::Error> {
+    let settings_json = serde_json::to_value(settings)?;
     let m: MatchModel =
-        sqlx::query_as(r#"UPDATE matches SET (state, board) = ($2, $3) WHERE id = $1 RETURNING *"#)
+        sqlx::query_as(r#"UPDATE matches SET settings = $2 WHERE id = $1 RETURNING *"#)
             .bind(id)
-            .bind(state)
-            .bind(board_json)
+            .bind(settings_json)
             .fetch_one(db)
             .await
             .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
 
     Ok(m)
 }
+
+pub async fn crud_create_series(db: &Pool<Postgres>, best_of: usize) -> Result<SeriesModel> {
+    let target_wins = SeriesSchema::target_wins(best_of)?;
+    let s: SeriesModel = query_as(
+        r#"
+        INSERT INTO series (id, best_of, target_wins)
+        VALUES ($1, $2, $3)
+        RETURNING *
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(best_of as i32)
+    .bind(target_wins as i32)
+    .fetch_one(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(s)
+}
+
+pub async fn crud_get_series(db: &Pool<Postgres>, id: Uuid) -> Result<SeriesModel> {
+    let s: SeriesModel = query_as(r#"SELECT * FROM series WHERE id = $1"#)
+        .bind(id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(s)
+}
+
+pub async fn crud_update_series(db: &Pool<Postgres>, series: &SeriesSchema) -> Result<SeriesModel> {
+    let s: SeriesModel = query_as(
+        r#"
+        UPDATE series SET (state, x_wins, o_wins, ties) = ($2, $3, $4, $5)
+        WHERE id = $1
+        RETURNING *
+        "#,
+    )
+    .bind(series.id)
+    .bind(series.status)
+    .bind(series.x_wins as i32)
+    .bind(series.o_wins as i32)
+    .bind(series.ties as i32)
+    .fetch_one(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(s)
+}
+
+pub async fn crud_get_queued_matches(db: &Pool<Postgres>) -> Result<Vec<QueuedMatchModel>> {
+    let queue: Vec<QueuedMatchModel> =
+        query_as(r#"SELECT * FROM queued_matches ORDER BY position"#)
+            .fetch_all(db)
+            .await
+            .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(queue)
+}
+
+pub async fn crud_create_queued_match(
+    db: &Pool<Postgres>,
+    queued: &CreateQueuedMatchSchema,
+) -> Result<QueuedMatchModel> {
+    let settings_json = serde_json::to_value(queued.settings)?;
+
+    let q: QueuedMatchModel = query_as(
+        r#"
+        INSERT INTO queued_matches (id, position, settings, best_of, starts_at)
+        VALUES ($1, (SELECT COALESCE(MAX(position), -1) + 1 FROM queued_matches), $2, $3, $4)
+        RETURNING *
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(settings_json)
+    .bind(queued.best_of.map(|b| b as i32))
+    .bind(queued.starts_at)
+    .fetch_one(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(q)
+}
+
+// Sets each queued match's position to its index in `ids`. Returns false without changing
+// anything unless `ids` lists every queued match exactly once.
+pub async fn crud_reorder_queued_matches(db: &Pool<Postgres>, ids: &[Uuid]) -> Result<bool> {
+    let mut tx = db.begin().await?;
+    let queued: Vec<(Uuid,)> = query_as(r#"SELECT id FROM queued_matches FOR UPDATE"#)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    let mut queued: Vec<Uuid> = queued.into_iter().map(|(id,)| id).collect();
+    let mut requested = ids.to_vec();
+    queued.sort();
+    requested.sort();
+    if queued != requested {
+        return Ok(false);
+    }
+
+    for (position, id) in ids.iter().enumerate() {
+        sqlx::query(r#"UPDATE queued_matches SET position = $2 WHERE id = $1"#)
+            .bind(id)
+            .bind(position as i32)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+    }
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn crud_delete_queued_match(db: &Pool<Postgres>, id: Uuid) -> Result<QueuedMatchModel> {
+    let q: QueuedMatchModel = query_as(r#"DELETE FROM queued_matches WHERE id = $1 RETURNING *"#)
+        .bind(id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(q)
+}
+
+// Removes and returns the first queued match that is due to start. Scheduled matches that
+// are due go ahead of the rest of the queue so they start on time.
+pub async fn crud_pop_queued_match(db: &Pool<Postgres>) -> Result<Option<QueuedMatchModel>> {
+    let q: Option<QueuedMatchModel> = query_as(
+        r#"
+        DELETE FROM queued_matches
+        WHERE id = (
+            SELECT id FROM queued_matches
+            WHERE starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP
+            ORDER BY starts_at IS NULL, position
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *
+        "#,
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(q)
+}
+
+// Whether a queued match with a start time is due
+pub async fn crud_scheduled_match_due(db: &Pool<Postgres>) -> Result<bool> {
+    let (due,): (bool,) = query_as(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM queued_matches
+            WHERE starts_at IS NOT NULL AND starts_at <= CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query model from db: {}", e))?;
+
+    Ok(due)
+}
+
+pub async fn crud_create_audit_entry(
+    db: &Pool<Postgres>,
+    entry: &CreateAuditEntrySchema,
+) -> Result<AuditEntryModel> {
+    let a: AuditEntryModel = query_as(
+        r#"
+        INSERT INTO audit_log (id, actor, action, match_id, board_before, board_after, details)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(&entry.actor)
+    .bind(&entry.action)
+    .bind(entry.match_id)
+    .bind(&entry.board_before)
+    .bind(&entry.board_after)
+    .bind(&entry.details)
+    .fetch_one(db)
+    .await
+    .map_err(|e| anyhow!("Unable to insert audit entry: {}", e))?;
+
+    Ok(a)
+}
+
+// Newest first. Every filter is optional.
+pub async fn crud_get_audit_entries(
+    db: &Pool<Postgres>,
+    query: &AuditQuery,
+) -> Result<Vec<AuditEntryModel>> {
+    let entries: Vec<AuditEntryModel> = query_as(
+        r#"
+        SELECT * FROM audit_log
+        WHERE ($1::uuid IS NULL OR match_id = $1)
+          AND ($2::text IS NULL OR actor = $2)
+          AND ($3::timestamptz IS NULL OR created_at >= $3)
+          AND ($4::timestamptz IS NULL OR created_at < $4)
+        ORDER BY created_at DESC
+        LIMIT $5 OFFSET $6
+        "#,
+    )
+    .bind(query.match_id)
+    .bind(&query.actor)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(query.limit.unwrap_or(50) as i64)
+    .bind(query.offset.unwrap_or(0) as i64)
+    .fetch_all(db)
+    .await
+    .map_err(|e| anyhow!("Unable to query audit log: {}", e))?;
+
+    Ok(entries)
+}
@@ -1,7 +1,99 @@
+use std::fmt;
+
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
+use uuid::Uuid;
+
+// The match changed since the version an update was based on
+#[derive(Debug)]
+pub struct VersionConflict {
+    pub match_id: Uuid,
+    pub version: u32,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Match {} was updated by someone else, it is no longer at version {}",
+            self.match_id, self.version
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+// Why a vote or move was refused. Serialized as a stable code for clients.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveError {
+    OutOfRange,
+    NotYourTurn,
+    MatchOver,
+    SectionClosed,
+    CellTaken,
+    // Two-phase turns take section votes first, then cell votes
+    VotingOnSection,
+    VotingOnCell,
+    // No turn is running. It is paused, waiting for players, being resolved or between games.
+    TurnClosed,
+    // Each connection gets one vote per turn, or per phase of a two-phase turn
+    AlreadyVoted,
+}
+
+impl MoveError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MoveError::OutOfRange => StatusCode::BAD_REQUEST,
+            _ => StatusCode::CONFLICT,
+        }
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            MoveError::OutOfRange => "Invalid section or cell index",
+            MoveError::NotYourTurn => "Not this team's turn",
+            MoveError::MatchOver => "Board is not interactive",
+            MoveError::SectionClosed => "Section is not interactive",
+            MoveError::CellTaken => "Cell is not interactive",
+            MoveError::VotingOnSection => "Voting is on a section, not a cell",
+            MoveError::VotingOnCell => "Voting is on a cell, not a section",
+            MoveError::TurnClosed => "Voting is closed for this turn",
+            MoveError::AlreadyVoted => "Already voted this turn",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+impl IntoResponse for MoveError {
+    fn into_response(self) -> Response {
+        let code = serde_json::to_value(self).unwrap_or_default();
+        error_response(self.status(), code.as_str().unwrap_or_default(), self)
+    }
+}
+
+// Body of error responses that clients are expected to handle
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+}
+
+pub fn error_response(status: StatusCode, code: &str, message: impl fmt::Display) -> Response {
+    let body = ErrorResponse {
+        code: code.to_string(),
+        message: message.to_string(),
+    };
+    (status, Json(body)).into_response()
+}
 
 // Make our own error that wraps `anyhow::Error`.
 pub struct AppError(anyhow::Error);
@@ -9,6 +101,10 @@ pub struct AppError(anyhow::Error);
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some(conflict) = self.0.downcast_ref::<VersionConflict>() {
+            return (StatusCode::CONFLICT, conflict.to_string()).into_response();
+        }
+
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
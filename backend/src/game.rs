@@ -0,0 +1,247 @@
+use anyhow::Result;
+use crossbeam::atomic::AtomicCell;
+use sqlx::postgres;
+use tokio::sync::broadcast;
+
+use crate::crud::{crud_get_match, crud_update_match, crud_update_match_settings};
+use crate::error::{MoveError, VersionConflict};
+use crate::schema::{
+    Board, MatchSchema, MatchSettings, Snapshot, SnapshotResponse, Team, TeamBroadcast,
+    TeamConnection, TimeBanks, VotePhase,
+};
+
+// Owns a room's current match and its votes. Every move is validated, persisted and
+// broadcast through here, whether it comes from the turn timer or the HTTP API.
+pub struct Game {
+    db: postgres::PgPool,
+    pub snapshot: Snapshot,
+    pub match_schema: AtomicCell<MatchSchema>,
+    pub vote_phase: AtomicCell<VotePhase>,
+    // The board before a two-phase turn locked it to one section
+    unlocked_board: AtomicCell<Option<Board>>,
+    // Only set for matches with time control
+    pub banks: AtomicCell<Option<TimeBanks>>,
+    pub snap_tx: TeamBroadcast<SnapshotResponse>,
+    pub match_tx: broadcast::Sender<MatchSchema>,
+}
+
+impl Game {
+    pub fn new(db: postgres::PgPool, match_schema: MatchSchema) -> Self {
+        let (match_tx, _match_rx) = broadcast::channel(4096);
+
+        let game = Self {
+            db,
+            snapshot: Snapshot::new(),
+            match_schema: AtomicCell::new(match_schema),
+            vote_phase: AtomicCell::new(VotePhase::Cell),
+            unlocked_board: AtomicCell::new(None),
+            banks: AtomicCell::new(None),
+            snap_tx: TeamBroadcast::new(100),
+            match_tx,
+        };
+        game.reset_banks();
+        game.begin_turn();
+        game
+    }
+
+    pub fn validate_move(&self, section: usize, cell: usize, team: Team) -> Result<(), MoveError> {
+        if self.vote_phase.load() != VotePhase::Cell {
+            return Err(MoveError::VotingOnSection);
+        }
+        self.match_schema
+            .load()
+            .board
+            .validate_move((section, cell), team)
+    }
+
+    pub fn validate_section_vote(&self, section: usize, team: Team) -> Result<(), MoveError> {
+        if self.vote_phase.load() != VotePhase::Section {
+            return Err(MoveError::VotingOnCell);
+        }
+        self.match_schema
+            .load()
+            .board
+            .validate_section(section, team)
+    }
+
+    // Counts a cell vote, one per connection and turn. A captain's vote is also the team's pick.
+    // Returns the turn the vote was counted in, the same counter ballots are kept by.
+    pub fn vote_cell(
+        &self,
+        voter: &TeamConnection,
+        section: usize,
+        cell: usize,
+        is_captain: bool,
+    ) -> Result<usize, MoveError> {
+        self.validate_move(section, cell, voter.team)?;
+        let turn = self.snapshot.turn();
+        if !self.snapshot.record_voter(voter.id) {
+            return Err(MoveError::AlreadyVoted);
+        }
+        self.snapshot.increment(section, cell);
+        if is_captain {
+            self.snapshot.captain_cell.store(Some((section, cell)));
+        }
+        Ok(turn)
+    }
+
+    pub fn vote_section(
+        &self,
+        voter: &TeamConnection,
+        section: usize,
+        is_captain: bool,
+    ) -> Result<usize, MoveError> {
+        self.validate_section_vote(section, voter.team)?;
+        let turn = self.snapshot.turn();
+        if !self.snapshot.record_voter(voter.id) {
+            return Err(MoveError::AlreadyVoted);
+        }
+        self.snapshot.increment_section(section);
+        if is_captain {
+            self.snapshot.captain_section.store(Some(section));
+        }
+        Ok(turn)
+    }
+
+    // Two-phase turns start with a section vote when more than one section is playable
+    pub fn begin_turn(&self) {
+        let curr_match = self.match_schema.load();
+        let phase = if curr_match.settings.two_phase && curr_match.board.interactive_sections() > 1
+        {
+            VotePhase::Section
+        } else {
+            VotePhase::Cell
+        };
+        self.vote_phase.store(phase);
+        self.unlocked_board.store(None);
+    }
+
+    // The board the current turn started from, ignoring any section lock. Moves made on
+    // behalf of the team, such as skips, start from here so the lock doesn't outlive the turn.
+    pub fn turn_board(&self) -> Board {
+        self.unlocked_board
+            .load()
+            .unwrap_or(self.match_schema.load().board)
+    }
+
+    // Refills the time banks for the current match
+    pub fn reset_banks(&self) {
+        let time_control = self.match_schema.load().settings.time_control;
+        self.banks.store(time_control.map(|tc| TimeBanks::new(&tc)));
+    }
+
+    // Commits the move with the most votes, if anyone voted
+    pub async fn commit_winning_move(&self) -> Result<Option<MatchSchema>> {
+        tracing::debug!(
+            "Committing winning move. Snapshot: {:?}",
+            self.snapshot.load()
+        );
+
+        match self.snapshot.winning_move() {
+            Some(coords) => {
+                let board = self.match_schema.load().board.get_updated(coords)?;
+                self.commit(board).await.map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Saves the board, broadcasts it and starts the next turn
+    pub async fn commit(&self, board: Board) -> Result<MatchSchema> {
+        let curr_match = self.match_schema.load();
+        let m = match crud_update_match(
+            &self.db,
+            curr_match.id,
+            curr_match.version,
+            board.status,
+            board,
+        )
+        .await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                // Catch up with whoever changed the match so the next commit can succeed
+                if e.is::<VersionConflict>() {
+                    if let Err(e) = self.reload().await {
+                        tracing::error!("Unable to reload match {}: {:?}", curr_match.id, e);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let updated = MatchSchema::try_from(&m)?;
+
+        let tally = self.snapshot.load();
+        self.snapshot.reset();
+        self.match_schema.store(updated);
+        self.begin_turn();
+
+        // Blind matches reveal the tally only now that the move is committed
+        let _ = self.match_tx.send(updated);
+        let _ = self.snap_tx.send_all(SnapshotResponse {
+            revealed: curr_match.settings.blind.then_some(tally),
+            ..SnapshotResponse::new(&self.snapshot, self.vote_phase.load())
+        });
+
+        Ok(updated)
+    }
+
+    // Replaces the board with the stored one. Votes cast on the old board are dropped.
+    async fn reload(&self) -> Result<()> {
+        let m = crud_get_match(&self.db, self.match_schema.load().id).await?;
+        let stored = MatchSchema::try_from(&m)?;
+        self.snapshot.reset();
+        self.match_schema.store(stored);
+        self.begin_turn();
+
+        let _ = self.match_tx.send(stored);
+        let _ = self.snap_tx.send_all(SnapshotResponse::new(
+            &self.snapshot,
+            self.vote_phase.load(),
+        ));
+        Ok(())
+    }
+
+    // Saves new rules for the current match and broadcasts them. The board is left alone.
+    pub async fn update_settings(&self, settings: MatchSettings) -> Result<MatchSchema> {
+        let mut curr_match = self.match_schema.load();
+        crud_update_match_settings(&self.db, curr_match.id, &settings).await?;
+        curr_match.settings = settings;
+        self.match_schema.store(curr_match);
+
+        let _ = self.match_tx.send(curr_match);
+        Ok(curr_match)
+    }
+
+    // Switches to another match, or the same match with a new board, and clears all votes
+    pub fn start_match(&self, match_schema: MatchSchema) {
+        self.snapshot.reset();
+        self.match_schema.store(match_schema);
+        self.reset_banks();
+        self.begin_turn();
+
+        let _ = self.match_tx.send(match_schema);
+        let _ = self.snap_tx.send_all(SnapshotResponse::new(
+            &self.snapshot,
+            self.vote_phase.load(),
+        ));
+    }
+
+    // Ends the section phase of a two-phase turn by restricting the board to the winning section
+    pub fn lock_section(&self, section: usize) -> Result<()> {
+        let mut match_schema = self.match_schema.load();
+        let unlocked = match_schema.board;
+        match_schema.board = unlocked.with_locked_section(section)?;
+        self.match_schema.store(match_schema);
+        self.unlocked_board.store(Some(unlocked));
+        self.vote_phase.store(VotePhase::Cell);
+        self.snapshot.reset_voters();
+
+        let _ = self.match_tx.send(match_schema);
+        let _ = self
+            .snap_tx
+            .send_all(SnapshotResponse::new(&self.snapshot, VotePhase::Cell));
+
+        Ok(())
+    }
+}
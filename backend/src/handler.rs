@@ -2,39 +2,100 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc; // Add this import
 
 use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::rejection::QueryRejection;
 use axum::extract::ws::Message;
 use axum::extract::ws::WebSocket;
 use axum::extract::Path;
 use axum::extract::WebSocketUpgrade;
 use axum::http::StatusCode;
+use axum::Extension;
 use axum::Json;
 use axum::{
     extract::{Query, State},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use chrono::Duration as ChronoDuration;
+use chrono::DateTime;
+use chrono::TimeDelta;
 use chrono::Utc;
+use futures::stream::SplitStream;
 use futures::SinkExt;
 use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
 use tokio::time::Duration;
 use tokio::time::Instant;
 use uuid::Uuid;
 
+use crate::auth::Admin;
+use crate::crud::crud_create_audit_entry;
 use crate::crud::crud_create_match;
+use crate::crud::crud_create_queued_match;
+use crate::crud::crud_create_series;
+use crate::crud::crud_delete_queued_match;
+use crate::crud::crud_get_audit_entries;
 use crate::crud::crud_get_latest_match;
 use crate::crud::crud_get_match;
 use crate::crud::crud_get_matches;
+use crate::crud::crud_get_queued_matches;
+use crate::crud::crud_get_series;
+use crate::crud::crud_pop_queued_match;
+use crate::crud::crud_reorder_queued_matches;
+use crate::crud::crud_scheduled_match_due;
 use crate::crud::crud_update_match;
+use crate::crud::crud_update_match_settings;
+use crate::crud::crud_update_series;
+use crate::error::error_response;
 use crate::error::AppError;
+use crate::error::MoveError;
+use crate::error::VersionConflict;
+use crate::phase::GamePhase;
+use crate::phase::PhaseContext;
+use crate::phase::PhaseTimings;
+use crate::protocol::ClientMessage;
+use crate::protocol::Encoding;
+use crate::protocol::ServerMessage;
+use crate::protocol::Session;
+use crate::protocol::SnapshotStream;
+use crate::protocol::Welcome;
+use crate::protocol::DELTA;
+use crate::protocol::HELLO_TIMEOUT;
+use crate::room::generate_invite_code;
+use crate::room::AdminCommand;
+use crate::room::Room;
+use crate::schema::AdjustTimerSchema;
+use crate::schema::AuditEntrySchema;
+use crate::schema::AuditQuery;
+use crate::schema::Ballot;
 use crate::schema::Board;
 use crate::schema::Coords;
-use crate::schema::IncrementRequest;
+use crate::schema::CreateAuditEntrySchema;
+use crate::schema::CreateQueuedMatchSchema;
+use crate::schema::CreateRoomResponse;
+use crate::schema::CreateRoomSchema;
+use crate::schema::EndMatchSchema;
+use crate::schema::FlagPolicy;
+use crate::schema::InviteQuery;
 use crate::schema::MatchSchema;
+use crate::schema::MatchSettings;
+use crate::schema::MatchTiming;
+use crate::schema::QueuedMatchSchema;
+use crate::schema::ReorderQueueSchema;
+use crate::schema::RoomResponse;
+use crate::schema::SeriesSchema;
 use crate::schema::SnapshotResponse;
 use crate::schema::Status;
+use crate::schema::Team;
+use crate::schema::TeamConnection;
 use crate::schema::TeamsResponse;
-use crate::schema::TimerResponse;
+use crate::schema::TimeControl;
+use crate::schema::VotePhase;
+use crate::schema::VoteQuery;
 use crate::{schema::Pagination, AppState};
 
 pub async fn get_matches_handler(
@@ -69,11 +130,40 @@ pub async fn get_match_by_id_handler(
     Ok(Json(crud_get_match(&data.db, match_id).await?))
 }
 
+// Bodies that may be left out. Only a missing body means the defaults, anything that
+// doesn't parse is refused.
+fn optional_json<T: DeserializeOwned + Default>(body: &Bytes) -> serde_json::Result<T> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body)
+}
+
 pub async fn create_match_handler(
     State(data): State<Arc<AppState>>,
+    body: Bytes,
 ) -> Result<impl IntoResponse, AppError> {
-    let m = crud_create_match(&data.db).await?;
-    Ok(Json(MatchSchema::try_from(&m)?))
+    let settings: MatchSettings = match optional_json(&body) {
+        Ok(settings) => settings,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                e,
+            ))
+        }
+    };
+    if let Err(e) = settings.validate() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_settings",
+            e,
+        ));
+    }
+    let settings = settings.with_default_timing(data.config.timing);
+    let board = Board::new_starting(settings.starting_team.pick(None));
+    let m = crud_create_match(&data.db, board, settings, None).await?;
+    Ok(Json(MatchSchema::try_from(&m)?).into_response())
 }
 
 pub async fn get_latest_match_handler(
@@ -86,144 +176,711 @@ pub async fn get_latest_match_handler(
 pub async fn commit_match_from_snapshot_handler(
     Path(match_id): Path<Uuid>,
     State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
 ) -> Result<impl IntoResponse, AppError> {
-    let coords = data
-        .snapshot
-        .find_max_indices()
-        .ok_or_else(|| anyhow!("No moves found"))?;
+    run_admin_command(&data, &admin, match_id, AdminCommand::Commit).await
+}
 
-    let m = crud_get_match(&data.db, match_id).await?;
-    let m = MatchSchema::try_from(&m)?;
+pub async fn reset_match_board_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+) -> Result<impl IntoResponse, AppError> {
+    if data.room_for_match(match_id).is_some() {
+        return run_admin_command(&data, &admin, match_id, AdminCommand::Reset).await;
+    }
 
-    let board = m.board.get_updated(coords)?;
+    // Matches that aren't being played in a room only need the db update.
+    // Keep the team that started the match.
+    let m = MatchSchema::try_from(&crud_get_match(&data.db, match_id).await?)?;
+    let board = Board::new_starting(m.starting_team);
+    let updated = crud_update_match(&data.db, match_id, m.version, board.status, board).await?;
 
-    let m = crud_update_match(&data.db, match_id, board.status, board).await?;
+    let entry = CreateAuditEntrySchema::new(&admin.name, "reset", match_id, &m.board, &board);
+    audit(&data.db, entry).await;
 
-    data.snapshot.reset();
+    Ok(Json(MatchSchema::try_from(&updated)?).into_response())
+}
 
-    Ok(Json(MatchSchema::try_from(&m)?))
+// Runs a command in the loop of the room playing the match
+async fn run_admin_command(
+    data: &AppState,
+    admin: &Admin,
+    match_id: Uuid,
+    command: AdminCommand,
+) -> Result<Response, AppError> {
+    let room = data
+        .room_for_match(match_id)
+        .ok_or_else(|| anyhow!("No room is playing this match"))?;
+
+    let before = room.game.match_schema.load().board;
+    if let Err(e) = room.command(command).await {
+        return Ok(command_error_response(e));
+    }
+    let after = room.game.match_schema.load();
+
+    // The command's fields, tagged with its name
+    let details = serde_json::to_value(command)?;
+    let action = details["action"].as_str().unwrap_or_default();
+    let entry = CreateAuditEntrySchema::new(&admin.name, action, match_id, &before, &after.board);
+    audit(&data.db, entry.with_details(details)).await;
+
+    Ok(Json(after).into_response())
 }
 
-pub async fn reset_match_board_handler(
+// Moves the board refused get the same codes as votes. Anything else the loop refused
+// conflicts with the state of the match.
+fn command_error_response(e: anyhow::Error) -> Response {
+    if let Some(e) = e.downcast_ref::<MoveError>() {
+        return e.into_response();
+    }
+    if let Some(e) = e.downcast_ref::<VersionConflict>() {
+        return error_response(StatusCode::CONFLICT, "version_conflict", e);
+    }
+    error_response(StatusCode::CONFLICT, "command_rejected", e)
+}
+
+// The action has already happened, so a failed write is logged rather than returned
+async fn audit(db: &PgPool, entry: CreateAuditEntrySchema) {
+    if let Err(e) = crud_create_audit_entry(db, &entry).await {
+        tracing::error!(
+            "Unable to audit {} by {}: {:?}",
+            entry.action,
+            entry.actor,
+            e
+        );
+    }
+}
+
+// Every filter is optional, but one that doesn't parse is refused rather than dropped
+pub async fn get_audit_log_handler(
+    query: Result<Query<AuditQuery>, QueryRejection>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let query = match query {
+        Ok(Query(query)) => query,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                e,
+            ))
+        }
+    };
+    let entries = crud_get_audit_entries(&data.db, &query).await?;
+    let entries: Vec<AuditEntrySchema> = entries.iter().map(AuditEntrySchema::from).collect();
+
+    Ok(Json(serde_json::json!({
+        "count": entries.len(),
+        "data": entries
+    }))
+    .into_response())
+}
+
+pub async fn pause_match_handler(
     Path(match_id): Path<Uuid>,
     State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
 ) -> Result<impl IntoResponse, AppError> {
-    let board = Board::new();
-    let m = crud_update_match(&data.db, match_id, board.status, board).await?;
-    data.snapshot.reset();
-    let schema = MatchSchema::try_from(&m)?;
-    data.match_schema.store(schema);
-    data.snap_tx
-        .send(SnapshotResponse {
-            snap: data.snapshot.load(),
-            your_team: None,
-        })
-        .map_err(|_| anyhow!("Failed to send snapshot response"))?;
-    data.match_tx
-        .send(schema)
-        .map_err(|_| anyhow!("Failed to send match updates to clients"))?;
-    Ok(Json(schema))
+    run_admin_command(&data, &admin, match_id, AdminCommand::Pause).await
 }
 
-pub async fn get_snapshot_handler(
+pub async fn resume_match_handler(
+    Path(match_id): Path<Uuid>,
     State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
 ) -> Result<impl IntoResponse, AppError> {
-    Ok(Json(data.snapshot.load()))
+    run_admin_command(&data, &admin, match_id, AdminCommand::Resume).await
 }
 
+pub async fn skip_turn_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+) -> Result<impl IntoResponse, AppError> {
+    run_admin_command(&data, &admin, match_id, AdminCommand::SkipTurn).await
+}
+
+pub async fn force_move_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(coords): Json<Coords>,
+) -> Result<impl IntoResponse, AppError> {
+    let command = AdminCommand::ForceMove {
+        section: coords.section,
+        cell: coords.cell,
+    };
+    run_admin_command(&data, &admin, match_id, command).await
+}
+
+pub async fn end_match_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(body): Json<EndMatchSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let command = AdminCommand::EndMatch {
+        status: body.status,
+    };
+    run_admin_command(&data, &admin, match_id, command).await
+}
+
+pub async fn adjust_timer_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(body): Json<AdjustTimerSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Err(e) = body.validate() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            e,
+        ));
+    }
+    let command = AdminCommand::AdjustTimer { secs: body.secs };
+    run_admin_command(&data, &admin, match_id, command).await
+}
+
+// Takes effect from the next turn when the match is being played
+pub async fn update_match_timing_handler(
+    Path(match_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(timing): Json<MatchTiming>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Err(e) = timing.validate() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_settings",
+            e,
+        ));
+    }
+
+    if data.room_for_match(match_id).is_some() {
+        let command = AdminCommand::UpdateTiming { timing };
+        return run_admin_command(&data, &admin, match_id, command).await;
+    }
+
+    // Matches that aren't being played in a room only need the db update
+    let m = MatchSchema::try_from(&crud_get_match(&data.db, match_id).await?)?;
+    let mut settings = m.settings;
+    settings.timing = Some(timing);
+    let updated = crud_update_match_settings(&data.db, match_id, &settings).await?;
+    let schema = MatchSchema::try_from(&updated)?;
+
+    let details = serde_json::json!({ "before": m.settings.timing, "after": timing });
+    let entry =
+        CreateAuditEntrySchema::new(&admin.name, "update_timing", match_id, &m.board, &m.board);
+    audit(&data.db, entry.with_details(details)).await;
+
+    Ok(Json(schema).into_response())
+}
+
+// Without a room id these act on the default room. Private rooms need their invite code.
+fn find_room(
+    data: &AppState,
+    room_id: Option<Uuid>,
+    invite: &InviteQuery,
+) -> Result<Option<Arc<Room>>> {
+    let room = match room_id {
+        Some(room_id) => data
+            .room(room_id)
+            .ok_or_else(|| anyhow!("Room {} not found", room_id))?,
+        None => data.default_room(),
+    };
+
+    Ok(room.admits(invite.code.as_deref()).then_some(room))
+}
+
+const INVALID_INVITE: (StatusCode, &str) = (StatusCode::FORBIDDEN, "Invalid invite code");
+
+pub async fn get_snapshot_handler(
+    Query(invite): Query<InviteQuery>,
+    State(data): State<Arc<AppState>>,
+) -> Response {
+    room_snapshot(&data, None, &invite)
+}
+
+// A room id that doesn't parse is rejected rather than falling back to the default room
+pub async fn get_room_snapshot_handler(
+    Path(room_id): Path<Uuid>,
+    Query(invite): Query<InviteQuery>,
+    State(data): State<Arc<AppState>>,
+) -> Response {
+    room_snapshot(&data, Some(room_id), &invite)
+}
+
+fn room_snapshot(data: &AppState, room_id: Option<Uuid>, invite: &InviteQuery) -> Response {
+    let room = match find_room(data, room_id, invite) {
+        Ok(Some(room)) => room,
+        Ok(None) => return INVALID_INVITE.into_response(),
+        Err(e) => return error_response(StatusCode::NOT_FOUND, "room_not_found", e),
+    };
+    let settings = room.game.match_schema.load().settings;
+    if settings.blind || settings.fog {
+        return (
+            StatusCode::FORBIDDEN,
+            "Votes are hidden until the move is committed",
+        )
+            .into_response();
+    }
+    Json(room.game.snapshot.load()).into_response()
+}
+
+// Votes for a cell the same way a WebSocket client would
 pub async fn update_snapshot_handler(
-    Query(params): Query<Coords>,
+    vote: Result<Query<VoteQuery>, QueryRejection>,
+    Query(invite): Query<InviteQuery>,
+    State(data): State<Arc<AppState>>,
+) -> Response {
+    cast_vote(&data, None, vote, &invite)
+}
+
+pub async fn update_room_snapshot_handler(
+    Path(room_id): Path<Uuid>,
+    vote: Result<Query<VoteQuery>, QueryRejection>,
+    Query(invite): Query<InviteQuery>,
+    State(data): State<Arc<AppState>>,
+) -> Response {
+    cast_vote(&data, Some(room_id), vote, &invite)
+}
+
+fn cast_vote(
+    data: &AppState,
+    room_id: Option<Uuid>,
+    vote: Result<Query<VoteQuery>, QueryRejection>,
+    invite: &InviteQuery,
+) -> Response {
+    let vote = match vote {
+        Ok(Query(vote)) => vote,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalid_request", e),
+    };
+
+    let room = match find_room(data, room_id, invite) {
+        Ok(Some(room)) => room,
+        Ok(None) => {
+            return error_response(StatusCode::FORBIDDEN, "invalid_invite", INVALID_INVITE.1)
+        }
+        Err(e) => return error_response(StatusCode::NOT_FOUND, "room_not_found", e),
+    };
+
+    let Some(voter) = room.teams.voter(vote.voter_token) else {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "unknown_voter",
+            "Voter token is not tied to a connection in this room",
+        );
+    };
+    let is_captain = is_acting_captain(&room, &voter);
+    if let Err(e) = room.ensure_accepting_votes().and_then(|_| {
+        room.game
+            .vote_cell(&voter, vote.section, vote.cell, is_captain)
+    }) {
+        return e.into_response();
+    }
+
+    wake_if_turn_can_close(&room);
+    send_live_snapshot(&room);
+    StatusCode::OK.into_response()
+}
+
+pub async fn create_room_handler(
+    State(data): State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let body: CreateRoomSchema = match optional_json(&body) {
+        Ok(body) => body,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                e,
+            ))
+        }
+    };
+    if let Err(e) = body
+        .settings
+        .validate()
+        .and_then(|_| SeriesSchema::validate_best_of(body.best_of))
+    {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_settings",
+            e,
+        ));
+    }
+    let settings = body.settings.with_default_timing(data.config.timing);
+
+    let series = create_series(&data.db, body.best_of).await?;
+
+    let starting_team = match series {
+        Some(s) => s.next_starting_team(),
+        None => settings.starting_team.pick(None),
+    };
+    let m = crud_create_match(
+        &data.db,
+        Board::new_starting(starting_team),
+        settings,
+        series.map(|s| s.id),
+    )
+    .await?;
+    let schema = MatchSchema::try_from(&m)?;
+
+    let invite_code = body.private.then(generate_invite_code);
+    let room = Arc::new(Room::new(
+        data.db.clone(),
+        schema,
+        false,
+        invite_code.clone(),
+        series,
+    ));
+    spawn_room(data, room);
+
+    Ok(Json(CreateRoomResponse {
+        match_schema: schema,
+        invite_code,
+    })
+    .into_response())
+}
+
+pub async fn get_series_handler(
+    Path(series_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let s = crud_get_series(&data.db, series_id).await?;
+    Ok(Json(SeriesSchema::from(&s)))
+}
+
+async fn create_series(db: &PgPool, best_of: Option<usize>) -> Result<Option<SeriesSchema>> {
+    let Some(best_of) = best_of else {
+        return Ok(None);
+    };
+
+    let s = crud_create_series(db, best_of).await?;
+    Ok(Some(SeriesSchema::from(&s)))
+}
+
+// The queue feeds the public room once its current match is over
+pub async fn get_queue_handler(
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let queue = crud_get_queued_matches(&data.db).await?;
+    let queue: Result<Vec<QueuedMatchSchema>> = queue.iter().map(|q| q.try_into()).collect();
+    let queue = queue.map_err(|e| anyhow!("Unable to parse queued matches: {}", e))?;
+
+    Ok(Json(serde_json::json!({
+        "count": queue.len(),
+        "data": queue
+    })))
+}
+
+pub async fn create_queued_match_handler(
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(mut body): Json<CreateQueuedMatchSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Err(e) = body
+        .settings
+        .validate()
+        .and_then(|_| SeriesSchema::validate_best_of(body.best_of))
+    {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_settings",
+            e,
+        ));
+    }
+    body.settings = body.settings.with_default_timing(data.config.timing);
+
+    let q = QueuedMatchSchema::try_from(&crud_create_queued_match(&data.db, &body).await?)?;
+    let entry = CreateAuditEntrySchema::without_match(&admin.name, "queue_match");
+    audit(&data.db, entry.with_details(serde_json::to_value(&q)?)).await;
+
+    Ok(Json(q).into_response())
+}
+
+pub async fn reorder_queue_handler(
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+    Json(body): Json<ReorderQueueSchema>,
+) -> Result<Response, AppError> {
+    if !crud_reorder_queued_matches(&data.db, &body.ids).await? {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_queue_order",
+            "The order must list every queued match exactly once",
+        ));
+    }
+    let entry = CreateAuditEntrySchema::without_match(&admin.name, "reorder_queue");
+    audit(
+        &data.db,
+        entry.with_details(serde_json::json!({ "ids": body.ids })),
+    )
+    .await;
+
+    Ok(get_queue_handler(State(data)).await?.into_response())
+}
+
+pub async fn cancel_queued_match_handler(
+    Path(queued_id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Extension(admin): Extension<Admin>,
+) -> Result<impl IntoResponse, AppError> {
+    let q = QueuedMatchSchema::try_from(&crud_delete_queued_match(&data.db, queued_id).await?)?;
+    let entry = CreateAuditEntrySchema::without_match(&admin.name, "cancel_queued_match");
+    audit(&data.db, entry.with_details(serde_json::to_value(&q)?)).await;
+
+    Ok(Json(q))
+}
+
+// Private rooms are left out of the directory
+pub async fn get_rooms_handler(
     State(data): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, AppError> {
-    data.snapshot.increment(params.section, params.cell);
-    Ok(StatusCode::OK)
+    let rooms: Vec<RoomResponse> = data
+        .rooms
+        .iter()
+        .filter(|room| !room.is_private())
+        .map(|room| room.summary())
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "count": rooms.len(),
+        "data": rooms
+    })))
 }
 
 pub async fn handle_websocket(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket_connection(socket, state))
+    let room = state.default_room();
+    ws.on_upgrade(|socket| handle_socket_connection(socket, room))
+}
+
+pub async fn handle_room_websocket(
+    ws: WebSocketUpgrade,
+    Path(match_id): Path<Uuid>,
+    Query(invite): Query<InviteQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let Some(room) = state.room(match_id) else {
+        let message = format!("Room {} not found", match_id);
+        return error_response(StatusCode::NOT_FOUND, "room_not_found", message);
+    };
+
+    if !room.admits(invite.code.as_deref()) {
+        return INVALID_INVITE.into_response();
+    }
+
+    ws.on_upgrade(|socket| handle_socket_connection(socket, room))
+        .into_response()
+}
+
+// Waits briefly for a hello. Returns the agreed session and any other message the client
+// opened with, or `None` if the client left before saying anything.
+async fn handshake(receiver: &mut SplitStream<WebSocket>) -> Option<(Session, Option<String>)> {
+    let text = match tokio::time::timeout(HELLO_TIMEOUT, receiver.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => text,
+        Ok(Some(Ok(_))) | Err(_) => return Some((Session::legacy(), None)),
+        Ok(Some(Err(_))) | Ok(None) => return None,
+    };
+
+    match serde_json::from_str::<ClientMessage>(&text) {
+        Ok(ClientMessage::Hello {
+            version,
+            capabilities,
+        }) => Some((Session::negotiate(version, &capabilities), None)),
+        _ => Some((Session::legacy(), Some(text))),
+    }
+}
+
+// Full state of the room as seen by a newly connected client
+fn welcome(
+    room: &Room,
+    session: &Session,
+    team_connection: &TeamConnection,
+    voter_token: Uuid,
+    ballot: &Ballot,
+) -> Welcome {
+    let (x_team_size, o_team_size) = room.teams.team_lens();
+    Welcome {
+        version: session.version,
+        capabilities: session.capabilities.clone(),
+        your_team: team_connection.team,
+        voter_token,
+        match_schema: room.game.match_schema.load(),
+        snapshot: SnapshotResponse {
+            your_team: Some(team_connection.team),
+            ..blind_snapshot(room, ballot, initial_snapshot(room, team_connection.team))
+        },
+        timer: room.timer(),
+        phase: room.phase.load(),
+        teams: TeamsResponse {
+            x_team_size,
+            o_team_size,
+            is_captain: is_acting_captain(room, team_connection),
+        },
+        series: room.series.load(),
+    }
+}
+
+// Registers a room and runs its turn loop until the room is closed
+pub fn spawn_room(state: Arc<AppState>, room: Arc<Room>) {
+    state.rooms.insert(room.id, room.clone());
+    tokio::spawn(async move {
+        if let Err(e) = run_match_updates(room.clone()).await {
+            tracing::error!("Failed to run match updates: {}", e);
+        }
+        state.rooms.remove(&room.id);
+        tracing::info!("Room {} closed", room.id);
+    });
 }
 
-async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
+async fn handle_socket_connection(socket: WebSocket, room: Arc<Room>) {
+    // Split the socket into sender and receiver
+    let (mut sender, mut receiver) = socket.split();
+
+    // Agree on a protocol version before joining, older clients skip this
+    let Some((session, first_message)) = handshake(&mut receiver).await else {
+        return;
+    };
+    let encoding = session.encoding();
+
     // Add the connection to a team.
-    let team_connection = state.teams.assign_team();
+    let team_connection = room.teams.assign_team();
+    let voter_token = room.teams.issue_voter_token(&team_connection);
 
     // Subscribe to broadcast channel
     // This allows the connection to receive updates, which in this case are snapshots
-    let mut snap_rx = state.snap_tx.subscribe();
+    let mut snap_rx = room.game.snap_tx.subscribe(team_connection.team);
 
     // Subscribe to match broadcast channel
     // This allows the connection to receive match updates
-    let mut match_rx = state.match_tx.subscribe();
+    let mut match_rx = room.game.match_tx.subscribe();
 
     // Subscribe to team broadcast channel
     // This allows the connection to receive team size updates
-    let mut teams_rx = state.teams_tx.subscribe();
+    let mut teams_rx = room.teams_tx.subscribe();
 
     // Subscribe to timer broadcast channel
     // This allows the connection to receive timer updates
-    let mut timer_rx = state.timer_tx.subscribe();
+    let mut timer_rx = room.timer_tx.subscribe();
+
+    // Subscribe to phase broadcast channel
+    // This allows the connection to receive game phase transitions
+    let mut phase_rx = room.phase_tx.subscribe();
 
-    let (team_x_len, team_o_len) = state.teams.team_lens();
+    // Subscribe to series broadcast channel
+    // This allows the connection to receive series standings
+    let mut series_rx = room.series_tx.subscribe();
+
+    let (team_x_len, team_o_len) = room.teams.team_lens();
     tracing::info!(
         "New connection (Team {:?}). Total connections: {}",
         team_connection.team,
         team_x_len + team_o_len
     );
 
-    // Update the paused state on new connection
-    let enough_players = team_x_len > 0 && team_o_len > 0;
-    state.is_paused.store(!enough_players, Ordering::SeqCst);
+    // The match loop may be waiting for players
+    room.wake.notify_waiters();
 
-    // Split the socket into sender and receiver
-    let (mut sender, mut receiver) = socket.split();
+    // Votes cast by this connection, echoed back in place of the tally in blind mode
+    let ballot = Arc::new(Ballot::new());
 
-    // Send the most recent snapshot to this client
-    // This lets them know what team they are on
-    let initial_snap = state.snapshot.load();
-    if let Ok(initial_response) = serde_json::to_string(&SnapshotResponse {
-        snap: initial_snap,
-        your_team: Some(team_connection.team),
-    }) {
-        if sender.send(Message::Text(initial_response)).await.is_err() {
-            tracing::error!("Unable to send initial snapshot to client");
+    // Replies meant only for this client, such as vote acknowledgements
+    let (reply_tx, mut reply_rx) = mpsc::channel::<ServerMessage>(32);
+
+    // Set when the client asks for a full snapshot
+    let resync = Arc::new(Notify::new());
+
+    if session.is_legacy() {
+        // Send the most recent snapshot to this client
+        // This lets them know what team they are on
+        let initial_snap = SnapshotResponse {
+            your_team: Some(team_connection.team),
+            ..blind_snapshot(
+                &room,
+                &ballot,
+                initial_snapshot(&room, team_connection.team),
+            )
+        };
+        if let Some(initial_response) =
+            ServerMessage::Snapshot(Box::new(initial_snap)).to_message(encoding)
+        {
+            if sender.send(initial_response).await.is_err() {
+                tracing::error!("Unable to send initial snapshot to client");
+            }
         }
-    }
 
-    // Send this client the most recent timer state
-    let start = state.start.load();
-    let stop = state.stop.load();
-    let is_paused = state.is_paused.load(Ordering::SeqCst);
-    if let Ok(initial_timer) = serde_json::to_string(&TimerResponse {
-        start,
-        stop,
-        is_paused,
-    }) {
-        if sender.send(Message::Text(initial_timer)).await.is_err() {
-            tracing::error!("Unable to send initial timer to client");
+        // Send this client the most recent timer state
+        if let Some(initial_timer) = ServerMessage::Timer(room.timer()).to_message(encoding) {
+            if sender.send(initial_timer).await.is_err() {
+                tracing::error!("Unable to send initial timer to client");
+            }
+        }
+
+        // Send this client the current game phase
+        if let Some(initial_phase) = (ServerMessage::Phase {
+            phase: room.phase.load(),
+        })
+        .to_message(encoding)
+        {
+            if sender.send(initial_phase).await.is_err() {
+                tracing::error!("Unable to send initial phase to client");
+            }
+        }
+
+        // Send this client the standings if the match is part of a series
+        if let Some(series) = room.series.load() {
+            if let Some(initial_series) = ServerMessage::Series(series).to_message(encoding) {
+                if sender.send(initial_series).await.is_err() {
+                    tracing::error!("Unable to send initial series to client");
+                }
+            }
+        }
+    } else {
+        // Send this client everything at once along with the negotiated version
+        let welcome = welcome(&room, &session, &team_connection, voter_token, &ballot);
+        if let Some(msg) = ServerMessage::Welcome(Box::new(welcome)).to_message(Encoding::Json) {
+            if sender.send(msg).await.is_err() {
+                tracing::error!("Unable to send welcome to client");
+            }
         }
     }
 
     // Broadcast the current team sizes to all clients
-    let _ = state.teams_tx.send(TeamsResponse {
+    let _ = room.teams_tx.send(TeamsResponse {
         x_team_size: team_x_len,
         o_team_size: team_o_len,
+        is_captain: false,
     });
 
     // Spawn a task to handle broadcast snapshot and match messages
     // Handles sending messages from this server to the client
     let mut send_task = {
+        let room = room.clone();
+        let ballot = ballot.clone();
+        let resync = resync.clone();
         tokio::spawn(async move {
+            // Clients that asked for deltas only get the cells that changed
+            let mut deltas = session.supports(DELTA).then(SnapshotStream::default);
+
             // Continually loop to handle snap_rx and timer_rx messages, whichever comes first
             loop {
                 tokio::select! {
                     // Handle sending snapshot updates
                     snap = snap_rx.recv() => {
                         if let Ok(snap) = snap {
-                            if let Ok(msg) = serde_json::to_string(&snap) {
-                                if sender.send(Message::Text(msg)).await.is_err() {
+                            let snap = blind_snapshot(&room, &ballot, snap);
+                            let message = match &mut deltas {
+                                Some(stream) => stream.next(snap),
+                                None => ServerMessage::Snapshot(Box::new(snap)),
+                            };
+                            if let Some(msg) = message.to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
                                     break;
                                 }
                             }
@@ -231,11 +888,28 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
                             break;
                         }
                     },
+                    // Handle resending the full snapshot on request
+                    _ = resync.notified() => {
+                        let snap = blind_snapshot(
+                            &room,
+                            &ballot,
+                            initial_snapshot(&room, team_connection.team),
+                        );
+                        let message = match &mut deltas {
+                            Some(stream) => stream.keyframe(snap),
+                            None => ServerMessage::Snapshot(Box::new(snap)),
+                        };
+                        if let Some(msg) = message.to_message(encoding) {
+                            if sender.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
                     // Handle sending new match struct end of each turn
                     match_schema = match_rx.recv() => {
                         if let Ok(match_schema) = match_schema {
-                            if let Ok(msg) = serde_json::to_string(&match_schema) {
-                                if sender.send(Message::Text(msg)).await.is_err() {
+                            if let Some(msg) = ServerMessage::Match(match_schema).to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
                                     break;
                                 }
                             }
@@ -246,8 +920,12 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
                     // Handle sending team size updates
                     teams = teams_rx.recv() => {
                         if let Ok(teams) = teams {
-                            if let Ok(msg) = serde_json::to_string(&teams) {
-                                if sender.send(Message::Text(msg)).await.is_err() {
+                            let teams = TeamsResponse {
+                                is_captain: is_acting_captain(&room, &team_connection),
+                                ..teams
+                            };
+                            if let Some(msg) = ServerMessage::Teams(teams).to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
                                     break;
                                 }
                             }
@@ -258,8 +936,44 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
                     // handle the timer
                     timer = timer_rx.recv() => {
                         if let Ok(timer) = timer {
-                            if let Ok(msg) = serde_json::to_string(&timer) {
-                                if sender.send(Message::Text(msg)).await.is_err() {
+                            if let Some(msg) = ServerMessage::Timer(timer).to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                        } else {
+                            break;
+                        }
+                    }
+                    // Handle sending game phase transitions
+                    phase = phase_rx.recv() => {
+                        if let Ok(phase) = phase {
+                            if let Some(msg) = (ServerMessage::Phase { phase }).to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                        } else {
+                            break;
+                        }
+                    }
+                    // Handle sending replies to this client's own messages
+                    reply = reply_rx.recv() => {
+                        if let Some(reply) = reply {
+                            if let Some(msg) = reply.to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                        } else {
+                            break;
+                        }
+                    }
+                    // Handle sending series standings after each game
+                    series = series_rx.recv() => {
+                        if let Ok(series) = series {
+                            if let Some(msg) = ServerMessage::Series(series).to_message(encoding) {
+                                if sender.send(msg).await.is_err() {
                                     break;
                                 }
                             }
@@ -275,56 +989,110 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
     // Spawn a task to handle incoming messages
     // Handles receiving messages sent from the client to this server
     let mut receive_task = {
-        let state = state.clone();
+        let room = room.clone();
         tokio::spawn(async move {
             let mut last_broadcast = Instant::now();
             let mut needs_broadcast = false;
 
+            // A message sent in place of a hello is handled like any other
+            let mut receiver =
+                futures::stream::iter(first_message.map(|text| Ok(Message::Text(text))))
+                    .chain(receiver);
+
             while let Some(Ok(message)) = receiver.next().await {
                 if let Message::Text(text) = message {
-                    // If the message is a valid increment request then we increment and broadcast updates to clients
-                    // We also check that the current connection is on the team that is allowed to make the move
-                    if let Ok(request) = serde_json::from_str::<IncrementRequest>(&text) {
-                        match state.snapshot.validate_move(
-                            state.clone(),
+                    // If the message is a valid increment or section request then we increment and broadcast
+                    // updates to clients. We also check that the current connection is on the team that is
+                    // allowed to make the move. The client is told whether its vote counted.
+                    let is_captain = is_acting_captain(&room, &team_connection);
+                    let (section, cell, vote) = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Vote(request)) => (
                             request.section,
-                            request.cell,
-                            team_connection.team,
-                        ) {
-                            Ok(_) => {
-                                state.snapshot.increment(request.section, request.cell);
-                                needs_broadcast = true;
-
-                                // If enough time has passed since last broadcast then
-                                // we send the most updated state. This is a primitive form
-                                // of rate limiting and stops a client from spamming everyone.
-                                if needs_broadcast
-                                    && last_broadcast.elapsed() > Duration::from_millis(100)
-                                {
-                                    let new_snap = state.snapshot.load();
-                                    let _ = state.snap_tx.send(SnapshotResponse {
-                                        snap: new_snap,
-                                        your_team: None,
-                                    });
-                                    needs_broadcast = false;
-                                    last_broadcast = Instant::now();
-                                }
+                            Some(request.cell),
+                            room.ensure_accepting_votes().and_then(|_| {
+                                room.game.vote_cell(
+                                    &team_connection,
+                                    request.section,
+                                    request.cell,
+                                    is_captain,
+                                )
+                            }),
+                        ),
+                        Ok(ClientMessage::SectionVote(request)) => (
+                            request.section,
+                            None,
+                            room.ensure_accepting_votes().and_then(|_| {
+                                room.game.vote_section(
+                                    &team_connection,
+                                    request.section,
+                                    is_captain,
+                                )
+                            }),
+                        ),
+                        Ok(ClientMessage::Resync) => {
+                            resync.notify_one();
+                            continue;
+                        }
+                        Ok(ClientMessage::Hello { .. }) => {
+                            let reply = ServerMessage::error(
+                                "unexpected_hello",
+                                "Hello must be the first message",
+                            );
+                            if reply_tx.send(reply).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            let reply = ServerMessage::error("invalid_message", e);
+                            if reply_tx.send(reply).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+
+                    let reply = match vote {
+                        Ok(turn) => {
+                            match cell {
+                                Some(cell) => ballot.record(turn, section, cell),
+                                None => ballot.record_section(turn, section),
+                            }
+
+                            needs_broadcast = true;
+                            wake_if_turn_can_close(&room);
+
+                            // If enough time has passed since last broadcast then
+                            // we send the most updated state. This is a primitive form
+                            // of rate limiting and stops a client from spamming everyone.
+                            if needs_broadcast
+                                && last_broadcast.elapsed() > Duration::from_millis(100)
+                            {
+                                send_live_snapshot(&room);
+                                needs_broadcast = false;
+                                last_broadcast = Instant::now();
                             }
-                            Err(e) => {
-                                tracing::error!("Invalid move: {:?}", e);
+
+                            ServerMessage::Ack {
+                                turn,
+                                section,
+                                cell,
                             }
                         }
+                        Err(e) => {
+                            tracing::debug!("Rejected vote: {}", e);
+                            ServerMessage::rejected(e)
+                        }
+                    };
+                    if reply_tx.send(reply).await.is_err() {
+                        break;
                     }
                 }
             }
 
             // Don't forget final broadcast if needed
             if needs_broadcast {
-                let new_snap = state.snapshot.load();
-                let _ = state.snap_tx.send(SnapshotResponse {
-                    snap: new_snap,
-                    your_team: None,
-                });
+                send_live_snapshot(&room);
             }
         })
     };
@@ -344,17 +1112,17 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
     let _ = receive_task.await;
 
     // Cleanup connection on disconnect
-    state.teams.remove_connection(&team_connection);
-    let (team_x_len, team_o_len) = state.teams.team_lens();
+    room.teams.remove_connection(&team_connection);
+    let (team_x_len, team_o_len) = room.teams.team_lens();
 
-    // Update pause state based on team sizes
-    let enough_players = team_x_len > 0 && team_o_len > 0;
-    state.is_paused.store(!enough_players, Ordering::SeqCst);
+    // The game may need to pause, or the remaining members may now all have voted
+    room.wake.notify_waiters();
 
     // Send out updated team sizes
-    let _ = state.teams_tx.send(TeamsResponse {
+    let _ = room.teams_tx.send(TeamsResponse {
         x_team_size: team_x_len,
         o_team_size: team_o_len,
+        is_captain: false,
     });
 
     // Log connection close and decrement connection count
@@ -364,118 +1132,501 @@ async fn handle_socket_connection(socket: WebSocket, state: Arc<AppState>) {
     );
 }
 
-async fn send_match_updates(state: Arc<AppState>) -> Result<Status> {
-    // Get current match data
-    let match_schema = state.match_schema.load();
+// A team that runs out of time without voting loses or has a random move played for it
+async fn flag_team(room: Arc<Room>, time_control: TimeControl) -> Result<Status> {
+    let board = room.game.match_schema.load().board;
+    tracing::info!("Team {:?} ran out of time", board.current_team);
 
-    tracing::debug!(
-        "Sending match updates. Snapshot: {:?}",
-        state.snapshot.load()
-    );
+    let board = match (time_control.on_flag, board.random_move()) {
+        (FlagPolicy::RandomMove, Some(coords)) => board.get_updated(coords)?,
+        _ => board.forfeited(),
+    };
+    Ok(room.game.commit(board).await?.board.status)
+}
+
+async fn record_series_result(room: Arc<Room>, result: Status) -> Result<()> {
+    let Some(series) = room.series.load() else {
+        return Ok(());
+    };
 
-    // Only update match if snapshot is not empty
-    if let Some(coords) = state.snapshot.find_max_indices() {
-        let board = match_schema.board.get_updated(coords)?;
-        let updated_match =
-            crud_update_match(&state.db, match_schema.id, board.status, board).await?;
-        let updated_match_schema = MatchSchema::try_from(&updated_match)?;
+    let s = crud_update_series(&room.db, &series.with_result(result)).await?;
+    let series = SeriesSchema::from(&s);
+    room.series.store(Some(series));
+    let _ = room.series_tx.send(series);
 
-        // Send to all connected clients and update state
-        state
-            .match_tx
-            .send(updated_match_schema)
-            .map_err(|_| anyhow::anyhow!("Failed to send match updates to clients"))?;
+    Ok(())
+}
 
-        // Reset snapshot state
-        state.snapshot.reset();
-        state.match_schema.store(updated_match_schema);
+// Only the public room plays queued matches
+async fn next_queued_match(room: &Room) -> Result<Option<QueuedMatchSchema>> {
+    if !room.persistent {
+        return Ok(None);
+    }
 
-        state
-            .snap_tx
-            .send(SnapshotResponse {
-                snap: state.snapshot.load(),
-                your_team: None,
-            })
-            .map_err(|_| anyhow::anyhow!("Failed to send snapshot response"))?;
+    match crud_pop_queued_match(&room.db).await? {
+        Some(q) => Ok(Some(QueuedMatchSchema::try_from(&q)?)),
+        None => Ok(None),
+    }
+}
 
-        return Ok(updated_match_schema.board.status);
+// A due scheduled match ends the intermission or replaces a match nobody has played yet.
+// Undecided series are finished first.
+async fn scheduled_match_due(room: &Room, phase: GamePhase) -> bool {
+    let between_matches = match phase {
+        GamePhase::Intermission { .. } => true,
+        GamePhase::WaitingForPlayers {
+            paused_turn_ms: None,
+        } => room.game.match_schema.load().board.ply() == 0,
+        _ => false,
+    };
+    let series_undecided = room.series.load().is_some_and(|s| !s.status.is_complete());
+    if !room.persistent || !between_matches || series_undecided {
+        return false;
     }
 
-    Ok(Status::Pending)
+    crud_scheduled_match_due(&room.db)
+        .await
+        .inspect_err(|e| tracing::error!("Error checking the queue: {:?}", e))
+        .unwrap_or(false)
 }
 
-async fn create_and_send_new_match(state: Arc<AppState>) -> Result<()> {
-    let new_match = crud_create_match(&state.db).await?;
-    let new_match_schema = MatchSchema::try_from(&new_match)?;
-    state.match_schema.store(new_match_schema);
-    state
-        .match_tx
-        .send(new_match_schema)
-        .map_err(|_| anyhow::anyhow!("Failed to send match updates to clients"))?;
+async fn create_and_send_new_match(room: Arc<Room>) -> Result<()> {
+    let finished = room.game.match_schema.load();
+
+    // Play the next game of an undecided series, then whatever is due in the queue.
+    // Otherwise carry the rules of the finished match over to the next one.
+    let (settings, series) = match room.series.load().filter(|s| !s.status.is_complete()) {
+        Some(series) => (finished.settings, Some(series)),
+        None => match next_queued_match(&room).await? {
+            Some(queued) => {
+                let series = create_series(&room.db, queued.best_of).await?;
+                if let Some(series) = series {
+                    let _ = room.series_tx.send(series);
+                }
+                (queued.settings, series)
+            }
+            None => (finished.settings, None),
+        },
+    };
+    room.series.store(series);
+
+    let starting_team = match series {
+        Some(s) => s.next_starting_team(),
+        None => settings.starting_team.pick(Some(&finished)),
+    };
+
+    let new_match = crud_create_match(
+        &room.db,
+        Board::new_starting(starting_team),
+        settings,
+        series.map(|s| s.id),
+    )
+    .await?;
+    room.game.start_match(MatchSchema::try_from(&new_match)?);
 
     Ok(())
 }
 
-async fn send_timer_and_wait(state: Arc<AppState>, time: usize) -> Result<()> {
-    let start = Utc::now();
-    let stop = start + ChronoDuration::seconds(time as i64);
-    state.stop.store(stop);
-    state.start.store(start);
-    let _ = state.timer_tx.send(TimerResponse {
-        start,
-        stop,
-        is_paused: state.is_paused.load(Ordering::SeqCst),
-    });
+// Broadcasts the current tally mid-turn. In fog mode only the voting team receives it.
+fn send_live_snapshot(room: &Room) {
+    let curr_match = room.game.match_schema.load();
+    let snap = SnapshotResponse::new(&room.game.snapshot, room.game.vote_phase.load());
+    let _ = if curr_match.settings.fog {
+        room.game.snap_tx.send(curr_match.board.current_team, snap)
+    } else {
+        room.game.snap_tx.send_all(snap)
+    };
+}
+
+// Snapshot sent on connect. In fog mode the opposing team gets an empty tally.
+fn initial_snapshot(room: &Room, team: Team) -> SnapshotResponse {
+    let curr_match = room.game.match_schema.load();
+    if curr_match.settings.fog && curr_match.board.current_team != team {
+        return SnapshotResponse {
+            snap: [[0; 9]; 9],
+            sections: None,
+            voter_count: 0,
+            ..SnapshotResponse::new(&room.game.snapshot, room.game.vote_phase.load())
+        };
+    }
+
+    SnapshotResponse::new(&room.game.snapshot, room.game.vote_phase.load())
+}
+
+// In blind mode a client only sees its own votes until the move is committed
+fn blind_snapshot(room: &Room, ballot: &Ballot, snap: SnapshotResponse) -> SnapshotResponse {
+    if !room.game.match_schema.load().settings.blind {
+        return snap;
+    }
+
+    let turn = room.game.snapshot.turn();
+    SnapshotResponse {
+        snap: ballot.load(turn),
+        sections: snap.sections.map(|_| ballot.load_sections(turn)),
+        ..snap
+    }
+}
+
+fn is_acting_captain(room: &Room, connection: &TeamConnection) -> bool {
+    room.game.match_schema.load().settings.captain && room.teams.is_captain(connection)
+}
+
+// Whether the current match's early close rules or its captain have settled the turn
+fn turn_is_decided(room: &Room) -> bool {
+    let curr_match = room.game.match_schema.load();
+    let rules = curr_match.settings.early_close;
+
+    let members = room.teams.members(curr_match.board.current_team);
+    let all_voted = rules.all_voted
+        && !members.is_empty()
+        && members.iter().all(|id| room.game.snapshot.has_voted(&id));
+
+    let decided = match room.game.vote_phase.load() {
+        VotePhase::Section => rules.is_supermajority(&room.game.snapshot.load_sections()),
+        VotePhase::Cell => rules.is_supermajority(room.game.snapshot.load().as_flattened()),
+    };
+
+    // In captain mode the captain's pick is committed right away
+    let captain_acted = curr_match.settings.captain
+        && match room.game.vote_phase.load() {
+            VotePhase::Section => room.game.snapshot.captain_section.load().is_some(),
+            VotePhase::Cell => room.game.snapshot.captain_cell.load().is_some(),
+        };
+
+    all_voted || decided || captain_acted
+}
+
+fn has_votes(room: &Room) -> bool {
+    match room.game.vote_phase.load() {
+        VotePhase::Section => room.game.snapshot.winning_section().is_some(),
+        VotePhase::Cell => !room.game.snapshot.is_empty(),
+    }
+}
+
+// Wakes the match loop if a vote can end the turn right away
+fn wake_if_turn_can_close(room: &Room) {
+    if turn_is_decided(room) || room.phase.load().is_overtime(Utc::now()) {
+        room.wake.notify_waiters();
+    }
+}
+
+fn send_timer(room: &Room, start: DateTime<Utc>, stop: DateTime<Utc>) {
+    room.stop.store(stop);
+    room.start.store(start);
+    let _ = room.timer_tx.send(room.timer());
+}
+
+// Commits the winning vote, or picks the section in the first phase of a two-phase turn.
+// Returns whether the match is over.
+async fn resolve_turn(room: Arc<Room>) -> bool {
+    let curr_match = room.game.match_schema.load();
+    let team = curr_match.board.current_team;
+
+    let result = match (curr_match.settings.time_control, room.game.banks.load()) {
+        (Some(time_control), Some(banks)) if banks.get(team) == 0 && !has_votes(&room) => {
+            flag_team(room.clone(), time_control).await
+        }
+        _ if room.game.vote_phase.load() == VotePhase::Section => {
+            if let Some(section) = room.game.snapshot.winning_section() {
+                tracing::debug!("Section {} chosen, voting on cells", section);
+                if let Err(e) = room.game.lock_section(section) {
+                    tracing::error!("Error locking section: {:?}", e);
+                }
+            }
+            return false;
+        }
+        _ => room
+            .game
+            .commit_winning_move()
+            .await
+            .map(|m| m.map_or(Status::Pending, |m| m.board.status)),
+    };
+
+    match result {
+        Ok(status) => {
+            // The team that just moved earns its increment
+            if let (Some(time_control), Some(banks)) =
+                (curr_match.settings.time_control, room.game.banks.load())
+            {
+                let increment = time_control.increment_secs as i64 * 1000;
+                room.game
+                    .banks
+                    .store(Some(banks.with(team, banks.get(team) + increment)));
+            }
+            status.is_complete()
+        }
+        Err(e) => {
+            tracing::error!("Error sending match updates: {:?}", e);
+            false
+        }
+    }
+}
+
+// Charges the current team's bank for the time it spent voting
+fn charge_bank(room: &Room, deadline: DateTime<Utc>, now: DateTime<Utc>) {
+    if let Some(banks) = room.game.banks.load() {
+        let team = room.game.match_schema.load().board.current_team;
+        let left = (deadline - now).num_milliseconds();
+        room.game.banks.store(Some(banks.with(team, left)));
+    }
+}
+
+// Timings of the current match. With time control a turn lasts as long as the current team's bank.
+fn turn_timings(room: &Room) -> PhaseTimings {
+    let curr_match = room.game.match_schema.load();
+    let timings = PhaseTimings::new(&curr_match.settings.timing.unwrap_or_default());
+    match room.game.banks.load() {
+        Some(banks) => PhaseTimings {
+            turn: TimeDelta::milliseconds(banks.get(curr_match.board.current_team)),
+            ..timings
+        },
+        None => timings,
+    }
+}
+
+// Moves the room to its next phase and runs the side effects of entering it
+async fn enter_phase(room: Arc<Room>, from: GamePhase, to: GamePhase) -> bool {
+    let now = Utc::now();
+    let mut match_complete = false;
+
+    // The next match starts once the intermission is over
+    if let GamePhase::Intermission { .. } = from {
+        if let Err(e) = create_and_send_new_match(room.clone()).await {
+            tracing::error!("Error creating new match: {:?}", e);
+        }
+    }
+
+    if let GamePhase::Voting { deadline } = from {
+        charge_bank(&room, deadline, now);
+    }
+
+    room.phase.store(to);
+    let _ = room.phase_tx.send(to);
+
+    match to {
+        GamePhase::WaitingForPlayers { paused_turn_ms } | GamePhase::Paused { paused_turn_ms } => {
+            if let GamePhase::Paused { .. } = to {
+                tracing::info!("Game paused by an admin");
+            } else {
+                tracing::warn!("Waiting for players, game paused");
+            }
+            // Keep the interrupted turn's window so clients can show where it froze
+            match paused_turn_ms {
+                Some(_) => send_timer(&room, room.start.load(), room.stop.load()),
+                None => send_timer(&room, now, now),
+            }
+        }
+        GamePhase::Voting { deadline } => send_timer(&room, now, deadline),
+        GamePhase::Resolving => {
+            if let GamePhase::Voting { deadline } = from {
+                if now < deadline {
+                    tracing::info!("Turn decided early, closing turn");
+                    send_timer(&room, room.start.load(), now);
+                }
+            }
+            match_complete = resolve_turn(room.clone()).await;
+        }
+        GamePhase::Finished => {
+            let status = room.game.match_schema.load().board.status;
+            if let Err(e) = record_series_result(room.clone(), status).await {
+                tracing::error!("Error recording series result: {:?}", e);
+            }
+        }
+        GamePhase::Intermission { until } => {
+            tracing::info!("Match is complete, next match starts at {}", until);
+            send_timer(&room, now, until);
+        }
+    }
+
+    match_complete
+}
+
+// Applies an admin command between transitions of the match loop
+async fn apply_admin_command(
+    room: Arc<Room>,
+    phase: GamePhase,
+    command: AdminCommand,
+) -> Result<()> {
+    let now = Utc::now();
+
+    let board = room.game.turn_board();
+    let board = match command {
+        AdminCommand::Pause => {
+            room.halted.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        AdminCommand::Resume => {
+            room.halted.store(false, Ordering::SeqCst);
+            return Ok(());
+        }
+        AdminCommand::UpdateTiming { timing } => {
+            let mut settings = room.game.match_schema.load().settings;
+            settings.timing = Some(timing);
+            room.game.update_settings(settings).await?;
+            return Ok(());
+        }
+        AdminCommand::AdjustTimer { secs } => {
+            // Checked so an absurd adjustment is refused rather than panicking the loop
+            let delta =
+                TimeDelta::try_seconds(secs).ok_or_else(|| anyhow!("Invalid adjustment"))?;
+            let adjust_ms = |ms: i64| {
+                ms.checked_add(delta.num_milliseconds())
+                    .map(|ms| ms.max(0))
+                    .ok_or_else(|| anyhow!("Invalid adjustment"))
+            };
+            let next = match phase {
+                GamePhase::Voting { deadline } => GamePhase::Voting {
+                    deadline: deadline
+                        .checked_add_signed(delta)
+                        .ok_or_else(|| anyhow!("Invalid adjustment"))?
+                        .max(now),
+                },
+                GamePhase::WaitingForPlayers {
+                    paused_turn_ms: Some(ms),
+                } => GamePhase::WaitingForPlayers {
+                    paused_turn_ms: Some(adjust_ms(ms)?),
+                },
+                GamePhase::Paused {
+                    paused_turn_ms: Some(ms),
+                } => GamePhase::Paused {
+                    paused_turn_ms: Some(adjust_ms(ms)?),
+                },
+                _ => bail!("No turn is in progress"),
+            };
+            enter_phase(room, phase, next).await;
+            return Ok(());
+        }
+        AdminCommand::Commit => {
+            ensure!(
+                room.game.vote_phase.load() == VotePhase::Cell,
+                "Voting is on a section, not a cell"
+            );
+            let coords = room
+                .game
+                .snapshot
+                .winning_move()
+                .ok_or_else(|| anyhow!("No moves found"))?;
+            board.get_updated(coords)?
+        }
+        AdminCommand::Reset => Board::new_starting(room.game.match_schema.load().starting_team),
+        AdminCommand::SkipTurn => board.skipped(),
+        AdminCommand::ForceMove { section, cell } => board.get_updated((section, cell))?,
+        AdminCommand::EndMatch { status } => {
+            ensure!(status.is_complete(), "A match can only end with a result");
+            board.ended(status)
+        }
+    };
+
+    ensure!(
+        matches!(
+            phase,
+            GamePhase::Voting { .. }
+                | GamePhase::WaitingForPlayers { .. }
+                | GamePhase::Paused { .. }
+        ),
+        "The match is not being played"
+    );
+
+    // The interrupted turn is over, charge it to the team that was voting
+    if let GamePhase::Voting { deadline } = phase {
+        charge_bank(&room, deadline, now);
+    }
 
-    tokio::time::sleep(Duration::from_secs(time as u64)).await;
+    let status = room.game.commit(board).await?.board.status;
+    if let AdminCommand::Reset = command {
+        room.game.reset_banks();
+    }
+
+    let next = match phase {
+        _ if status.is_complete() => GamePhase::Finished,
+        GamePhase::Voting { .. } => GamePhase::Voting {
+            deadline: now + turn_timings(&room).turn,
+        },
+        GamePhase::Paused { .. } => GamePhase::Paused {
+            paused_turn_ms: None,
+        },
+        _ => GamePhase::WaitingForPlayers {
+            paused_turn_ms: None,
+        },
+    };
+    enter_phase(room, GamePhase::Resolving, next).await;
 
     Ok(())
 }
 
-pub async fn run_match_updates(state: Arc<AppState>) -> Result<()> {
+pub async fn run_match_updates(room: Arc<Room>) -> Result<()> {
     tracing::info!("Starting run match updates");
+    let mut commands = room
+        .take_commands()
+        .ok_or_else(|| anyhow!("Room {} already has a match loop", room.id))?;
+    let mut empty_since = Instant::now();
+    let mut match_complete = false;
 
     loop {
-        // Check conditions first
-        let (team_x_len, team_o_len) = state.teams.team_lens();
+        // Register for wake ups before reading any state so none are missed
+        let woken = room.wake.notified();
+        tokio::pin!(woken);
+        woken.as_mut().enable();
+
+        let (team_x_len, team_o_len) = room.teams.team_lens();
         tracing::debug!("Team sizes: X: {}, O: {}", team_x_len, team_o_len);
-        if team_x_len == 0 || team_o_len == 0 {
-            state.is_paused.store(true, Ordering::SeqCst);
-            tracing::warn!("Not enough players, game paused");
-            // Sleep for a shorter duration when waiting for players
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            continue;
-        }
 
-        if state.is_paused.load(Ordering::SeqCst) {
-            tracing::info!("Game is paused.");
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            continue;
+        // Close rooms that nobody has been in for five minutes
+        if team_x_len + team_o_len > 0 {
+            empty_since = Instant::now();
+        } else if !room.persistent && empty_since.elapsed() > Duration::from_secs(300) {
+            tracing::info!("Room {} is empty, closing", room.id);
+            return Ok(());
         }
 
-        if state.snapshot.is_empty() {
-            tracing::warn!("Snapshot is empty, not starting match updates");
-            tokio::time::sleep(Duration::from_secs(4)).await;
-            continue;
-        }
+        let timings = turn_timings(&room);
+        let min_players = room
+            .game
+            .match_schema
+            .load()
+            .settings
+            .timing
+            .unwrap_or_default()
+            .min_players_per_team
+            .max(1);
 
-        // Only sleep the full interval when actually running updates
-        tracing::debug!("Conditions met, starting match updates");
-        match send_match_updates(state.clone()).await {
-            Ok(status) => {
-                if status.is_complete() {
-                    tracing::info!("Match is complete, pausing game for 20 seconds");
-                    state.is_paused.store(true, Ordering::SeqCst);
-                    send_timer_and_wait(state.clone(), 30).await?;
-                    create_and_send_new_match(state.clone()).await?;
-                    state.is_paused.store(false, Ordering::SeqCst);
-                } else {
-                    send_timer_and_wait(state.clone(), 20).await?;
+        let phase = room.phase.load();
+        let scheduled_match_due = scheduled_match_due(&room, phase).await;
+        if scheduled_match_due {
+            if let GamePhase::WaitingForPlayers { .. } = phase {
+                tracing::info!("Scheduled match is due, replacing the unstarted match");
+                if let Err(e) = create_and_send_new_match(room.clone()).await {
+                    tracing::error!("Error creating new match: {:?}", e);
                 }
             }
-            Err(e) => {
-                tracing::error!("Error sending match updates: {:?}", e);
-                send_timer_and_wait(state.clone(), 20).await?;
+        }
+
+        let ctx = PhaseContext {
+            now: Utc::now(),
+            enough_players: team_x_len >= min_players && team_o_len >= min_players,
+            has_votes: has_votes(&room),
+            turn_decided: turn_is_decided(&room),
+            match_complete,
+            halted: room.is_halted(),
+            timed: room.game.banks.load().is_some(),
+            scheduled_match_due,
+        };
+
+        match phase.next(&ctx, &timings) {
+            Some(next) => {
+                tracing::debug!("Game phase {:?} -> {:?}", phase, next);
+                match_complete = enter_phase(room.clone(), phase, next).await;
+            }
+            None => {
+                let timeout = phase.timeout(ctx.now, &timings);
+                tokio::select! {
+                    _ = tokio::time::sleep(timeout.to_std().unwrap_or_default()) => {}
+                    _ = woken => {}
+                    Some((command, reply)) = commands.recv() => {
+                        tracing::info!("Admin command {:?} in phase {:?}", command, phase);
+                        let _ = reply.send(apply_admin_command(room.clone(), phase, command).await);
+                    }
+                }
             }
         }
     }
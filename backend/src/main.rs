@@ -1,25 +1,42 @@
+mod auth;
+mod config;
 mod crud;
 mod error;
+mod game;
 mod handler;
 mod model;
+mod phase;
+mod protocol;
+mod room;
 mod schema;
 
+use auth::require_admin;
 use axum::{
-    http::{header::CONTENT_TYPE, Method},
-    routing::{any, get, post},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Method,
+    },
+    middleware,
+    routing::{any, delete, get, post, put},
     Router,
 };
-use chrono::{DateTime, Utc};
-use crossbeam::atomic::AtomicCell;
-use crud::{crud_create_match, crud_get_latest_match};
+use config::Config;
+use crud::{crud_create_match, crud_get_latest_match, crud_get_series};
+use dashmap::DashMap;
 use handler::{
-    commit_match_from_snapshot_handler, create_match_handler, get_latest_match_handler,
-    get_match_by_id_handler, get_matches_handler, get_snapshot_handler, handle_websocket,
-    reset_match_board_handler, run_match_updates, update_snapshot_handler,
+    adjust_timer_handler, cancel_queued_match_handler, commit_match_from_snapshot_handler,
+    create_match_handler, create_queued_match_handler, create_room_handler, end_match_handler,
+    force_move_handler, get_audit_log_handler, get_latest_match_handler, get_match_by_id_handler,
+    get_matches_handler, get_queue_handler, get_room_snapshot_handler, get_rooms_handler,
+    get_series_handler, get_snapshot_handler, handle_room_websocket, handle_websocket,
+    pause_match_handler, reorder_queue_handler, reset_match_board_handler, resume_match_handler,
+    skip_turn_handler, spawn_room, update_match_timing_handler, update_room_snapshot_handler,
+    update_snapshot_handler,
 };
-use schema::{Board, MatchSchema, Snapshot, SnapshotResponse, Teams, TeamsResponse, TimerResponse};
+use room::Room;
+use schema::{Board, MatchSchema, MatchSettings, SeriesSchema};
 use sqlx::postgres;
-use tokio::{net::TcpListener, sync::broadcast};
+use tokio::net::TcpListener;
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::{DefaultOnResponse, TraceLayer},
@@ -28,52 +45,36 @@ use tracing::Level;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
-use std::sync::atomic::AtomicBool;
 use std::{sync::Arc, time::Duration};
 
 pub struct AppState {
     db: postgres::PgPool,
-    snapshot: Snapshot,
-    match_schema: AtomicCell<MatchSchema>,
-    snap_tx: broadcast::Sender<SnapshotResponse>,
-    teams_tx: broadcast::Sender<TeamsResponse>,
-    timer_tx: broadcast::Sender<TimerResponse>,
-    match_tx: broadcast::Sender<MatchSchema>,
-    teams: Teams,
-    is_paused: AtomicBool,
-    start: AtomicCell<DateTime<Utc>>,
-    stop: AtomicCell<DateTime<Utc>>,
+    config: Config,
+    rooms: DashMap<Uuid, Arc<Room>>,
+    // The public room served on `/ws`
+    default_room: Uuid,
 }
 
-impl Drop for AppState {
-    fn drop(&mut self) {
-        self.snap_tx
-            .send(SnapshotResponse {
-                snap: [[0; 9]; 9],
-                your_team: None,
-            })
-            .ok();
-        self.teams_tx
-            .send(TeamsResponse {
-                x_team_size: 0,
-                o_team_size: 0,
-            })
-            .ok();
-        self.timer_tx
-            .send(TimerResponse {
-                start: Utc::now(),
-                stop: Utc::now(),
-                is_paused: true,
-            })
-            .ok();
-        self.match_tx
-            .send(MatchSchema {
-                id: Uuid::new_v4(),
-                board: Board::new(),
-                created_at: Utc::now(),
-                updated_at: Utc::now(),
-            })
-            .ok();
+impl AppState {
+    // Looks a room up by its id or by the id of the match it is currently playing
+    fn room(&self, id: Uuid) -> Option<Arc<Room>> {
+        if let Some(room) = self.rooms.get(&id) {
+            return Some(room.clone());
+        }
+
+        self.room_for_match(id)
+    }
+
+    fn room_for_match(&self, match_id: Uuid) -> Option<Arc<Room>> {
+        self.rooms
+            .iter()
+            .find(|room| room.game.match_schema.load().id == match_id)
+            .map(|room| room.clone())
+    }
+
+    fn default_room(&self) -> Arc<Room> {
+        self.room(self.default_room)
+            .expect("default room is never removed")
     }
 }
 
@@ -89,6 +90,7 @@ async fn main() {
         .init();
 
     let db_connection_str = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let config = Config::from_env();
 
     // set up connection pool
     let pool = postgres::PgPoolOptions::new()
@@ -99,9 +101,9 @@ async fn main() {
         .expect("can't connect to database");
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_origin(Any)
-        .allow_headers([CONTENT_TYPE]);
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE]);
 
     let trace_layer =
         TraceLayer::new_for_http().on_response(DefaultOnResponse::new().level(Level::INFO));
@@ -109,18 +111,14 @@ async fn main() {
     // Run migrations
     sqlx::migrate!("./migrations").run(&pool).await.unwrap();
 
-    let (snap_tx, _snap_rx) = broadcast::channel(100);
-    let (teams_tx, _teams_rx) = broadcast::channel(100);
-    let (match_tx, _match_rx) = broadcast::channel(4096);
-    let (timer_tx, _timer_rx) = broadcast::channel(100);
-
     // Get the latest match state or create a new one
     let match_schema = match crud_get_latest_match(&pool).await {
         Ok(model) => MatchSchema::try_from(&model)
             .map_err(|e| anyhow::anyhow!("Failed to convert model to schema: {}", e))
             .unwrap(),
         Err(_) => {
-            let new_match = crud_create_match(&pool)
+            let settings = MatchSettings::default().with_default_timing(config.timing);
+            let new_match = crud_create_match(&pool, Board::new(), settings, None)
                 .await
                 .map_err(|e| anyhow::anyhow!("Failed to create new match: {}", e))
                 .unwrap();
@@ -131,44 +129,78 @@ async fn main() {
         }
     };
 
+    // Matches saved before timing was configurable use the server defaults
+    let match_schema = MatchSchema {
+        settings: match_schema.settings.with_default_timing(config.timing),
+        ..match_schema
+    };
+
+    // Pick the series back up if the latest match is part of one
+    let series = match match_schema.series_id {
+        Some(series_id) => crud_get_series(&pool, series_id)
+            .await
+            .map(|s| SeriesSchema::from(&s))
+            .ok(),
+        None => None,
+    };
+
+    let default_room = Arc::new(Room::new(pool.clone(), match_schema, true, None, series));
     let state = Arc::new(AppState {
         db: pool.clone(),
-        snapshot: Snapshot::new(),
-        match_schema: AtomicCell::new(match_schema),
-        snap_tx: snap_tx.clone(),
-        teams_tx: teams_tx.clone(),
-        match_tx: match_tx.clone(),
-        timer_tx: timer_tx.clone(),
-        teams: Teams::new(),
-        is_paused: AtomicBool::new(false),
-        start: AtomicCell::new(Utc::now()),
-        stop: AtomicCell::new(Utc::now()),
+        config,
+        rooms: DashMap::new(),
+        default_room: default_room.id,
     });
 
-    // Spawn the global 10 second timer.
-    let update_state = state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = run_match_updates(update_state).await {
-            tracing::error!("Failed to run match updates: {}", e);
-        }
-    });
+    // Spawn the default room and its turn timer.
+    spawn_room(state.clone(), default_room);
+
+    // Match control, only for requests with an admin API key
+    let admin = Router::new()
+        .route(
+            "/matches/:match_id/commit",
+            post(commit_match_from_snapshot_handler),
+        )
+        .route("/matches/:match_id/reset", post(reset_match_board_handler))
+        .route(
+            "/matches/:match_id/timing",
+            put(update_match_timing_handler),
+        )
+        .route("/matches/:match_id/pause", post(pause_match_handler))
+        .route("/matches/:match_id/resume", post(resume_match_handler))
+        .route("/matches/:match_id/skip", post(skip_turn_handler))
+        .route("/matches/:match_id/move", post(force_move_handler))
+        .route("/matches/:match_id/end", post(end_match_handler))
+        .route("/matches/:match_id/timer", post(adjust_timer_handler))
+        .route(
+            "/queue",
+            post(create_queued_match_handler).put(reorder_queue_handler),
+        )
+        .route("/queue/:queued_id", delete(cancel_queued_match_handler))
+        .route("/audit", get(get_audit_log_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
 
     // Build our application with some routes
     let app = Router::new()
         .route("/ws", any(handle_websocket))
-        .route("/api/matches/latest", get(get_latest_match_handler))
+        .route("/ws/:match_id", any(handle_room_websocket))
         .route(
-            "/api/matches",
-            get(get_matches_handler).post(create_match_handler),
+            "/api/rooms",
+            get(get_rooms_handler).post(create_room_handler),
         )
+        .route("/api/series/:series_id", get(get_series_handler))
+        .route("/api/queue", get(get_queue_handler))
         .route(
-            "/api/matches/:match_id",
-            get(get_match_by_id_handler).post(commit_match_from_snapshot_handler),
+            "/api/rooms/:room_id/snapshot",
+            get(get_room_snapshot_handler).put(update_room_snapshot_handler),
         )
+        .route("/api/matches/latest", get(get_latest_match_handler))
         .route(
-            "/api/matches/:match_id/reset",
-            post(reset_match_board_handler),
+            "/api/matches",
+            get(get_matches_handler).post(create_match_handler),
         )
+        .route("/api/matches/:match_id", get(get_match_by_id_handler))
+        .nest("/api/admin", admin)
         .route(
             "/api/snapshot",
             get(get_snapshot_handler).put(update_snapshot_handler),
@@ -12,6 +12,45 @@ pub struct MatchModel {
     pub id: Uuid,
     pub state: Status,
     pub board: Json<Value>,
+    pub settings: Json<Value>,
+    pub series_id: Option<Uuid>,
+    pub starting_team: Status,
+    pub version: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct SeriesModel {
+    pub id: Uuid,
+    pub state: Status,
+    pub best_of: i32,
+    pub target_wins: i32,
+    pub x_wins: i32,
+    pub o_wins: i32,
+    pub ties: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct QueuedMatchModel {
+    pub id: Uuid,
+    pub position: i32,
+    pub settings: Json<Value>,
+    pub best_of: Option<i32>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
+pub struct AuditEntryModel {
+    pub id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub match_id: Option<Uuid>,
+    pub board_before: Option<String>,
+    pub board_after: Option<String>,
+    pub details: Json<Value>,
+    pub created_at: DateTime<Utc>,
+}
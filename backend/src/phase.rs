@@ -0,0 +1,405 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::schema::MatchTiming;
+
+// Lifecycle of a room's current match. Transitions are broadcast to clients.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "game_phase", rename_all = "snake_case")]
+pub enum GamePhase {
+    // Each team needs at least one connected player. A turn interrupted by a
+    // pause keeps its remaining time and resumes from there.
+    WaitingForPlayers { paused_turn_ms: Option<i64> },
+    // Stopped by an admin. Keeps the interrupted turn's remaining time like `WaitingForPlayers`.
+    Paused { paused_turn_ms: Option<i64> },
+    // The current team votes until the deadline, or longer if nobody has voted yet
+    Voting { deadline: DateTime<Utc> },
+    // The winning vote is being committed
+    Resolving,
+    // The match is over
+    Finished,
+    // Break before the next match starts
+    Intermission { until: DateTime<Utc> },
+}
+
+// How long each phase lasts
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseTimings {
+    pub turn: TimeDelta,
+    pub intermission: TimeDelta,
+    // Fallback re-checks in case a wake up is missed
+    pub waiting_poll: TimeDelta,
+    pub overtime_poll: TimeDelta,
+}
+
+impl PhaseTimings {
+    pub fn new(timing: &MatchTiming) -> Self {
+        Self {
+            turn: TimeDelta::seconds(timing.turn_secs as i64),
+            intermission: TimeDelta::seconds(timing.intermission_secs as i64),
+            waiting_poll: TimeDelta::seconds(2),
+            overtime_poll: TimeDelta::seconds(timing.overtime_poll_secs as i64),
+        }
+    }
+}
+
+// Everything a transition depends on, gathered by the match loop
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseContext {
+    pub now: DateTime<Utc>,
+    pub enough_players: bool,
+    pub has_votes: bool,
+    // Early close rules or the captain have settled the turn
+    pub turn_decided: bool,
+    // Outcome of the last resolved vote
+    pub match_complete: bool,
+    // An admin has paused the match
+    pub halted: bool,
+    // Turns are limited by time banks, so the deadline ends the turn even without votes
+    pub timed: bool,
+    // A queued match with a start time is due, so the break ends early
+    pub scheduled_match_due: bool,
+}
+
+impl GamePhase {
+    // Returns the phase to move to, or `None` to stay in the current one
+    pub fn next(&self, ctx: &PhaseContext, timings: &PhaseTimings) -> Option<GamePhase> {
+        let voting = GamePhase::Voting {
+            deadline: ctx.now + timings.turn,
+        };
+
+        match *self {
+            GamePhase::WaitingForPlayers { paused_turn_ms } if ctx.halted => {
+                Some(GamePhase::Paused { paused_turn_ms })
+            }
+            GamePhase::WaitingForPlayers { paused_turn_ms } => {
+                let deadline = match paused_turn_ms {
+                    Some(ms) => ctx.now + TimeDelta::milliseconds(ms),
+                    None => ctx.now + timings.turn,
+                };
+                ctx.enough_players.then_some(GamePhase::Voting { deadline })
+            }
+            GamePhase::Voting { deadline } => {
+                let paused_turn_ms = Some(
+                    (deadline - ctx.now)
+                        .max(TimeDelta::zero())
+                        .num_milliseconds(),
+                );
+                if ctx.halted {
+                    Some(GamePhase::Paused { paused_turn_ms })
+                } else if !ctx.enough_players {
+                    Some(GamePhase::WaitingForPlayers { paused_turn_ms })
+                } else if ctx.has_votes && (ctx.turn_decided || ctx.now >= deadline) {
+                    Some(GamePhase::Resolving)
+                } else if ctx.timed && ctx.now >= deadline {
+                    // Out of time without a vote
+                    Some(GamePhase::Resolving)
+                } else {
+                    None
+                }
+            }
+            GamePhase::Paused { paused_turn_ms } => {
+                (!ctx.halted).then_some(GamePhase::WaitingForPlayers { paused_turn_ms })
+            }
+            GamePhase::Resolving if ctx.match_complete => Some(GamePhase::Finished),
+            GamePhase::Resolving => Some(voting),
+            GamePhase::Finished => Some(GamePhase::Intermission {
+                until: ctx.now + timings.intermission,
+            }),
+            GamePhase::Intermission { until } => (ctx.now >= until || ctx.scheduled_match_due)
+                .then_some(GamePhase::WaitingForPlayers {
+                    paused_turn_ms: None,
+                }),
+        }
+    }
+
+    // How long to wait for a wake up before checking for a transition again
+    pub fn timeout(&self, now: DateTime<Utc>, timings: &PhaseTimings) -> TimeDelta {
+        match *self {
+            GamePhase::WaitingForPlayers { .. } | GamePhase::Paused { .. } => timings.waiting_poll,
+            GamePhase::Voting { deadline } if deadline > now => deadline - now,
+            GamePhase::Voting { .. } => timings.overtime_poll,
+            // Polls so a scheduled match can cut the break short
+            GamePhase::Intermission { until } => {
+                (until - now).clamp(TimeDelta::zero(), timings.waiting_poll)
+            }
+            GamePhase::Resolving | GamePhase::Finished => TimeDelta::zero(),
+        }
+    }
+
+    // Clocks stop while waiting for players, when paused and between matches
+    pub fn is_paused(&self) -> bool {
+        matches!(
+            self,
+            GamePhase::WaitingForPlayers { .. }
+                | GamePhase::Paused { .. }
+                | GamePhase::Finished
+                | GamePhase::Intermission { .. }
+        )
+    }
+
+    // Votes are only cast while a turn is running. A paused or waiting turn is frozen.
+    pub fn accepts_votes(&self) -> bool {
+        matches!(self, GamePhase::Voting { .. })
+    }
+
+    // Time left on the clock, frozen while a turn is paused
+    pub fn remaining(&self, now: DateTime<Utc>) -> TimeDelta {
+        match *self {
+            GamePhase::WaitingForPlayers {
+                paused_turn_ms: Some(ms),
+            }
+            | GamePhase::Paused {
+                paused_turn_ms: Some(ms),
+            } => TimeDelta::milliseconds(ms),
+            GamePhase::Voting { deadline } => (deadline - now).max(TimeDelta::zero()),
+            GamePhase::Intermission { until } => (until - now).max(TimeDelta::zero()),
+            _ => TimeDelta::zero(),
+        }
+    }
+
+    pub fn is_overtime(&self, now: DateTime<Utc>) -> bool {
+        matches!(self, GamePhase::Voting { deadline } if now >= *deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings() -> PhaseTimings {
+        PhaseTimings {
+            turn: TimeDelta::seconds(30),
+            intermission: TimeDelta::seconds(10),
+            waiting_poll: TimeDelta::seconds(2),
+            overtime_poll: TimeDelta::seconds(5),
+        }
+    }
+
+    fn ctx(now: DateTime<Utc>) -> PhaseContext {
+        PhaseContext {
+            now,
+            enough_players: true,
+            has_votes: false,
+            turn_decided: false,
+            match_complete: false,
+            halted: false,
+            timed: false,
+            scheduled_match_due: false,
+        }
+    }
+
+    #[test]
+    fn waiting_starts_a_fresh_turn() {
+        let now = Utc::now();
+        let phase = GamePhase::WaitingForPlayers {
+            paused_turn_ms: None,
+        };
+
+        assert_eq!(
+            phase.next(&ctx(now), &timings()),
+            Some(GamePhase::Voting {
+                deadline: now + TimeDelta::seconds(30)
+            })
+        );
+    }
+
+    #[test]
+    fn waiting_resumes_the_paused_turn() {
+        let now = Utc::now();
+        let phase = GamePhase::WaitingForPlayers {
+            paused_turn_ms: Some(1500),
+        };
+
+        assert_eq!(
+            phase.next(&ctx(now), &timings()),
+            Some(GamePhase::Voting {
+                deadline: now + TimeDelta::milliseconds(1500)
+            })
+        );
+    }
+
+    #[test]
+    fn waiting_needs_players() {
+        let now = Utc::now();
+        let phase = GamePhase::WaitingForPlayers {
+            paused_turn_ms: None,
+        };
+        let ctx = PhaseContext {
+            enough_players: false,
+            ..ctx(now)
+        };
+
+        assert_eq!(phase.next(&ctx, &timings()), None);
+    }
+
+    #[test]
+    fn halting_a_turn_keeps_its_remaining_time() {
+        let now = Utc::now();
+        let phase = GamePhase::Voting {
+            deadline: now + TimeDelta::seconds(12),
+        };
+        let ctx = PhaseContext {
+            halted: true,
+            ..ctx(now)
+        };
+
+        assert_eq!(
+            phase.next(&ctx, &timings()),
+            Some(GamePhase::Paused {
+                paused_turn_ms: Some(12_000)
+            })
+        );
+    }
+
+    #[test]
+    fn losing_players_keeps_the_remaining_time() {
+        let now = Utc::now();
+        let phase = GamePhase::Voting {
+            deadline: now + TimeDelta::seconds(7),
+        };
+        let ctx = PhaseContext {
+            enough_players: false,
+            ..ctx(now)
+        };
+
+        assert_eq!(
+            phase.next(&ctx, &timings()),
+            Some(GamePhase::WaitingForPlayers {
+                paused_turn_ms: Some(7_000)
+            })
+        );
+    }
+
+    #[test]
+    fn resuming_returns_to_waiting() {
+        let now = Utc::now();
+        let phase = GamePhase::Paused {
+            paused_turn_ms: Some(4_000),
+        };
+
+        assert_eq!(
+            phase.next(&ctx(now), &timings()),
+            Some(GamePhase::WaitingForPlayers {
+                paused_turn_ms: Some(4_000)
+            })
+        );
+    }
+
+    #[test]
+    fn decided_turn_closes_early() {
+        let now = Utc::now();
+        let phase = GamePhase::Voting {
+            deadline: now + TimeDelta::seconds(20),
+        };
+        let undecided = PhaseContext {
+            has_votes: true,
+            ..ctx(now)
+        };
+        let decided = PhaseContext {
+            turn_decided: true,
+            ..undecided
+        };
+
+        assert_eq!(phase.next(&undecided, &timings()), None);
+        assert_eq!(phase.next(&decided, &timings()), Some(GamePhase::Resolving));
+    }
+
+    #[test]
+    fn deadline_closes_a_turn_with_votes() {
+        let now = Utc::now();
+        let phase = GamePhase::Voting { deadline: now };
+        let ctx = PhaseContext {
+            has_votes: true,
+            ..ctx(now)
+        };
+
+        assert_eq!(phase.next(&ctx, &timings()), Some(GamePhase::Resolving));
+    }
+
+    #[test]
+    fn deadline_without_votes_waits_unless_timed() {
+        let now = Utc::now();
+        let phase = GamePhase::Voting { deadline: now };
+        let timed = PhaseContext {
+            timed: true,
+            ..ctx(now)
+        };
+
+        assert_eq!(phase.next(&ctx(now), &timings()), None);
+        assert_eq!(phase.next(&timed, &timings()), Some(GamePhase::Resolving));
+    }
+
+    #[test]
+    fn finished_match_runs_through_intermission() {
+        let now = Utc::now();
+        let complete = PhaseContext {
+            match_complete: true,
+            ..ctx(now)
+        };
+
+        let finished = GamePhase::Resolving.next(&complete, &timings());
+        assert_eq!(finished, Some(GamePhase::Finished));
+
+        let intermission = GamePhase::Finished.next(&ctx(now), &timings());
+        let until = now + TimeDelta::seconds(10);
+        assert_eq!(intermission, Some(GamePhase::Intermission { until }));
+
+        let phase = GamePhase::Intermission { until };
+        assert_eq!(phase.next(&ctx(now), &timings()), None);
+        assert_eq!(
+            phase.next(&ctx(until), &timings()),
+            Some(GamePhase::WaitingForPlayers {
+                paused_turn_ms: None
+            })
+        );
+    }
+
+    #[test]
+    fn resolving_an_unfinished_match_starts_the_next_turn() {
+        let now = Utc::now();
+
+        assert_eq!(
+            GamePhase::Resolving.next(&ctx(now), &timings()),
+            Some(GamePhase::Voting {
+                deadline: now + TimeDelta::seconds(30)
+            })
+        );
+    }
+
+    #[test]
+    fn only_running_turns_accept_votes() {
+        let now = Utc::now();
+
+        assert!(GamePhase::Voting { deadline: now }.accepts_votes());
+        assert!(!GamePhase::Paused {
+            paused_turn_ms: Some(1_000)
+        }
+        .accepts_votes());
+        assert!(!GamePhase::WaitingForPlayers {
+            paused_turn_ms: None
+        }
+        .accepts_votes());
+        assert!(!GamePhase::Resolving.accepts_votes());
+        assert!(!GamePhase::Intermission { until: now }.accepts_votes());
+    }
+
+    #[test]
+    fn scheduled_match_ends_intermission_early() {
+        let now = Utc::now();
+        let phase = GamePhase::Intermission {
+            until: now + TimeDelta::seconds(10),
+        };
+        let ctx = PhaseContext {
+            scheduled_match_due: true,
+            ..ctx(now)
+        };
+
+        assert_eq!(
+            phase.next(&ctx, &timings()),
+            Some(GamePhase::WaitingForPlayers {
+                paused_turn_ms: None
+            })
+        );
+    }
+}
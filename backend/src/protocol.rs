@@ -0,0 +1,393 @@
+use std::fmt;
+
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::error::{ErrorResponse, MoveError};
+use crate::phase::GamePhase;
+use crate::schema::{
+    IncrementRequest, MatchSchema, SectionRequest, SeriesSchema, SnapshotResponse, Team,
+    TeamsResponse, TimerResponse,
+};
+
+// Version 1 is the tagged protocol spoken by clients that never send a hello.
+// Version 2 added the hello handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const LEGACY_VERSION: u32 = 1;
+
+// Optional features a client can ask for in its hello
+pub const MSGPACK: &str = "msgpack";
+pub const DELTA: &str = "delta";
+pub const CAPABILITIES: &[&str] = &[MSGPACK, DELTA];
+
+// Clients taking deltas get a full snapshot at least this often
+const KEYFRAME_INTERVAL: u64 = 50;
+
+// How long a new connection has to say hello before it is treated as a legacy client. Legacy
+// clients that stay quiet get their first messages this much later. Any message they send
+// ends the wait right away.
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Everything the server sends over a websocket. The `type` field tells clients how to
+// read the rest of the message, so new kinds can be added without breaking old clients.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Snapshot(Box<SnapshotResponse>),
+    Match(MatchSchema),
+    Teams(TeamsResponse),
+    Timer(TimerResponse),
+    // GamePhase carries its own `game_phase` tag, so it is nested under a field
+    Phase {
+        phase: GamePhase,
+    },
+    Series(SeriesSchema),
+    // Sent only to the voter once their vote has been counted. `turn` is the room's voting
+    // round, which also moves on when a turn is skipped.
+    Ack {
+        turn: usize,
+        section: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cell: Option<usize>,
+    },
+    // Sent only to the voter when their vote was refused
+    Rejected {
+        code: MoveError,
+        message: String,
+    },
+    // Sent only to the sender of a message the server could not read
+    Error(ErrorResponse),
+    // Full tally for clients taking deltas. Later deltas build on it.
+    Keyframe {
+        seq: u64,
+        snapshot: Box<SnapshotResponse>,
+    },
+    // Cells whose count changed since the message with the previous `seq`
+    SnapshotDelta {
+        seq: u64,
+        changes: Vec<CellChange>,
+        voter_count: usize,
+    },
+    // Reply to a hello, with everything the client needs to render the game. Always JSON so
+    // clients can read the agreed encoding before switching to it.
+    Welcome(Box<Welcome>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct CellChange {
+    pub section: usize,
+    pub cell: usize,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct Welcome {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    pub your_team: Team,
+    // Lets the client vote over HTTP as this connection
+    pub voter_token: Uuid,
+    #[serde(rename = "match")]
+    pub match_schema: MatchSchema,
+    pub snapshot: SnapshotResponse,
+    pub timer: TimerResponse,
+    pub phase: GamePhase,
+    pub teams: TeamsResponse,
+    pub series: Option<SeriesSchema>,
+}
+
+impl ServerMessage {
+    pub fn rejected(error: MoveError) -> Self {
+        ServerMessage::Rejected {
+            code: error,
+            message: error.to_string(),
+        }
+    }
+
+    pub fn error(code: &str, message: impl fmt::Display) -> Self {
+        ServerMessage::Error(ErrorResponse {
+            code: code.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    pub fn to_message(&self, encoding: Encoding) -> Option<Message> {
+        let message = match encoding {
+            Encoding::Json => serde_json::to_string(self)
+                .map(Message::Text)
+                .map_err(|e| e.to_string()),
+            // Field names are kept so messages decode to the same shape as the JSON ones
+            Encoding::MessagePack => rmp_serde::to_vec_named(self)
+                .map(Message::Binary)
+                .map_err(|e| e.to_string()),
+        };
+        match message {
+            Ok(message) => Some(message),
+            Err(e) => {
+                tracing::error!("Unable to serialize server message: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// Everything a client may send over a websocket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Must be the first message of a connection
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Vote(IncrementRequest),
+    SectionVote(SectionRequest),
+    // Asks for a keyframe after a missed or out of order delta
+    Resync,
+}
+
+// How server messages are written. JSON text unless the client asked for MessagePack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+// What a connection agreed on during the handshake
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    // Set when the client said hello, whatever version it asked for. It is owed a welcome.
+    pub hello: bool,
+}
+
+impl Session {
+    // Picks the highest version both sides speak and the capabilities both sides know
+    pub fn negotiate(version: u32, capabilities: &[String]) -> Self {
+        Self {
+            version: version.clamp(LEGACY_VERSION, PROTOCOL_VERSION),
+            capabilities: capabilities
+                .iter()
+                .filter(|c| CAPABILITIES.contains(&c.as_str()))
+                .cloned()
+                .collect(),
+            hello: true,
+        }
+    }
+
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_VERSION,
+            capabilities: Vec::new(),
+            hello: false,
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        if self.supports(MSGPACK) {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    // Clients that never said hello get the old initial messages instead of a welcome
+    pub fn is_legacy(&self) -> bool {
+        !self.hello
+    }
+}
+
+// Turns the snapshots sent to one connection into deltas against the previous one.
+// Anything but a change in counts, such as a new vote phase or a reveal, gets a keyframe.
+#[derive(Default)]
+pub struct SnapshotStream {
+    seq: u64,
+    last_keyframe: u64,
+    last: Option<SnapshotResponse>,
+}
+
+impl SnapshotStream {
+    pub fn next(&mut self, snap: SnapshotResponse) -> ServerMessage {
+        let Some(last) = &self.last else {
+            return self.keyframe(snap);
+        };
+        if last.phase != snap.phase
+            || last.sections.is_some()
+            || snap.sections.is_some()
+            || snap.revealed.is_some()
+            || self.seq + 1 - self.last_keyframe >= KEYFRAME_INTERVAL
+        {
+            return self.keyframe(snap);
+        }
+
+        let mut changes = Vec::new();
+        for (section, (before, after)) in last.snap.iter().zip(snap.snap.iter()).enumerate() {
+            for (cell, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+                if before != after {
+                    changes.push(CellChange {
+                        section,
+                        cell,
+                        count: *after,
+                    });
+                }
+            }
+        }
+
+        self.seq += 1;
+        let voter_count = snap.voter_count;
+        self.last = Some(snap);
+        ServerMessage::SnapshotDelta {
+            seq: self.seq,
+            changes,
+            voter_count,
+        }
+    }
+
+    pub fn keyframe(&mut self, snap: SnapshotResponse) -> ServerMessage {
+        self.seq += 1;
+        self.last_keyframe = self.seq;
+        self.last = Some(snap.clone());
+        ServerMessage::Keyframe {
+            seq: self.seq,
+            snapshot: Box::new(snap),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::VotePhase;
+
+    fn capabilities(names: &[&str]) -> Vec<String> {
+        names.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate_clamps_the_version() {
+        assert_eq!(Session::negotiate(0, &[]).version, LEGACY_VERSION);
+        assert_eq!(Session::negotiate(1, &[]).version, LEGACY_VERSION);
+        assert_eq!(Session::negotiate(2, &[]).version, PROTOCOL_VERSION);
+        assert_eq!(Session::negotiate(99, &[]).version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn negotiate_keeps_only_known_capabilities() {
+        let session = Session::negotiate(2, &capabilities(&["delta", "zstd", "msgpack"]));
+
+        assert_eq!(session.capabilities, capabilities(&["delta", "msgpack"]));
+        assert!(session.supports(DELTA));
+        assert!(!session.supports("zstd"));
+        assert_eq!(session.encoding(), Encoding::MessagePack);
+    }
+
+    fn snapshot(counts: &[(usize, usize, usize)]) -> SnapshotResponse {
+        let mut snap = [[0; 9]; 9];
+        for &(section, cell, count) in counts {
+            snap[section][cell] = count;
+        }
+        SnapshotResponse {
+            your_team: None,
+            snap,
+            phase: VotePhase::Cell,
+            sections: None,
+            voter_count: counts.len(),
+            revealed: None,
+        }
+    }
+
+    fn is_keyframe(message: &ServerMessage) -> bool {
+        matches!(message, ServerMessage::Keyframe { .. })
+    }
+
+    #[test]
+    fn stream_starts_with_a_keyframe() {
+        let mut stream = SnapshotStream::default();
+
+        assert!(matches!(
+            stream.next(snapshot(&[(0, 0, 1)])),
+            ServerMessage::Keyframe { seq: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn delta_lists_only_changed_cells() {
+        let mut stream = SnapshotStream::default();
+        stream.next(snapshot(&[(0, 0, 1), (4, 4, 2)]));
+
+        let ServerMessage::SnapshotDelta {
+            seq,
+            changes,
+            voter_count,
+        } = stream.next(snapshot(&[(0, 0, 1), (4, 4, 3), (8, 2, 1)]))
+        else {
+            panic!("expected a delta");
+        };
+
+        assert_eq!(seq, 2);
+        assert_eq!(voter_count, 3);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|c| (c.section, c.cell, c.count))
+            .collect();
+        assert_eq!(changes, vec![(4, 4, 3), (8, 2, 1)]);
+    }
+
+    #[test]
+    fn phase_change_forces_a_keyframe() {
+        let mut stream = SnapshotStream::default();
+        stream.next(SnapshotResponse {
+            phase: VotePhase::Section,
+            sections: Some([0; 9]),
+            ..snapshot(&[])
+        });
+
+        assert!(is_keyframe(&stream.next(snapshot(&[]))));
+    }
+
+    #[test]
+    fn reveal_forces_a_keyframe() {
+        let mut stream = SnapshotStream::default();
+        stream.next(snapshot(&[(0, 0, 1)]));
+
+        let revealed = SnapshotResponse {
+            revealed: Some([[1; 9]; 9]),
+            ..snapshot(&[(0, 0, 1)])
+        };
+        assert!(is_keyframe(&stream.next(revealed)));
+    }
+
+    #[test]
+    fn keyframe_is_sent_every_interval() {
+        let mut stream = SnapshotStream::default();
+        let keyframes: Vec<u64> = (0..=2 * KEYFRAME_INTERVAL)
+            .filter_map(|_| match stream.next(snapshot(&[])) {
+                ServerMessage::Keyframe { seq, .. } => Some(seq),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            keyframes,
+            vec![1, KEYFRAME_INTERVAL + 1, 2 * KEYFRAME_INTERVAL + 1]
+        );
+    }
+
+    #[test]
+    fn any_hello_is_welcomed() {
+        let old = Session::negotiate(1, &capabilities(&["msgpack"]));
+
+        assert!(!old.is_legacy());
+        assert_eq!(old.encoding(), Encoding::MessagePack);
+        assert!(Session::legacy().is_legacy());
+        assert_eq!(Session::legacy().encoding(), Encoding::Json);
+    }
+}
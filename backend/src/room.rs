@@ -0,0 +1,237 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use crossbeam::atomic::AtomicCell;
+use serde::Serialize;
+use sqlx::postgres;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use uuid::Uuid;
+
+use crate::error::MoveError;
+use crate::game::Game;
+use crate::phase::GamePhase;
+
+use crate::schema::{
+    Board, MatchSchema, MatchSettings, MatchTiming, RoomResponse, SeriesSchema, SnapshotResponse,
+    Status, Team, Teams, TeamsResponse, TimerResponse, VotePhase,
+};
+
+// A single game with its own crowd, votes, timer loop and channels
+pub struct Room {
+    // Id of the match the room was opened for. Stays the same across later matches.
+    pub id: Uuid,
+    pub db: postgres::PgPool,
+    // The current match and its votes
+    pub game: Game,
+    pub teams_tx: broadcast::Sender<TeamsResponse>,
+    pub timer_tx: broadcast::Sender<TimerResponse>,
+    pub series_tx: broadcast::Sender<SeriesSchema>,
+    pub teams: Teams,
+    pub phase: AtomicCell<GamePhase>,
+    pub phase_tx: broadcast::Sender<GamePhase>,
+    pub start: AtomicCell<DateTime<Utc>>,
+    pub stop: AtomicCell<DateTime<Utc>>,
+    // Wakes the match loop when votes or players may allow a transition
+    pub wake: Notify,
+    // Set by admins, keeps the match paused regardless of players
+    pub halted: AtomicBool,
+    // Admin commands run by the match loop so they never race a turn being resolved
+    commands: mpsc::Sender<AdminRequest>,
+    command_rx: Mutex<Option<mpsc::Receiver<AdminRequest>>>,
+    // Persistent rooms keep running when nobody is connected
+    pub persistent: bool,
+    // Private rooms can only be joined with this code
+    pub invite_code: Option<String>,
+    // Standings of the series the current match belongs to
+    pub series: AtomicCell<Option<SeriesSchema>>,
+}
+
+impl Room {
+    pub fn new(
+        db: postgres::PgPool,
+        match_schema: MatchSchema,
+        persistent: bool,
+        invite_code: Option<String>,
+        series: Option<SeriesSchema>,
+    ) -> Self {
+        let (teams_tx, _teams_rx) = broadcast::channel(100);
+        let (timer_tx, _timer_rx) = broadcast::channel(100);
+        let (series_tx, _series_rx) = broadcast::channel(100);
+        let (phase_tx, _phase_rx) = broadcast::channel(100);
+        let (commands, command_rx) = mpsc::channel(16);
+
+        Self {
+            id: match_schema.id,
+            game: Game::new(db.clone(), match_schema),
+            db,
+            teams_tx,
+            timer_tx,
+            series_tx,
+            teams: Teams::new(),
+            phase: AtomicCell::new(GamePhase::WaitingForPlayers {
+                paused_turn_ms: None,
+            }),
+            phase_tx,
+            start: AtomicCell::new(Utc::now()),
+            stop: AtomicCell::new(Utc::now()),
+            wake: Notify::new(),
+            halted: AtomicBool::new(false),
+            commands,
+            command_rx: Mutex::new(Some(command_rx)),
+            persistent,
+            invite_code,
+            series: AtomicCell::new(series),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.invite_code.is_some()
+    }
+
+    pub fn summary(&self) -> RoomResponse {
+        let match_schema = self.game.match_schema.load();
+        let (x_team_size, o_team_size) = self.teams.team_lens();
+
+        RoomResponse {
+            id: self.id,
+            match_id: match_schema.id,
+            status: match_schema.board.status,
+            turn: match_schema.board.ply() + 1,
+            current_team: match_schema.board.current_team,
+            x_team_size,
+            o_team_size,
+            time_remaining_ms: self.phase.load().remaining(Utc::now()).num_milliseconds(),
+            phase: self.phase.load(),
+        }
+    }
+
+    pub fn timer(&self) -> TimerResponse {
+        let phase = self.phase.load();
+        TimerResponse {
+            start: self.start.load(),
+            stop: self.stop.load(),
+            is_paused: phase.is_paused(),
+            remaining_ms: phase.remaining(Utc::now()).num_milliseconds(),
+            banks: self.game.banks.load(),
+        }
+    }
+
+    // Sends a command to the match loop and waits for it to be applied
+    pub async fn command(&self, command: AdminCommand) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.commands
+            .send((command, reply_tx))
+            .await
+            .map_err(|_| anyhow!("Room is closed"))?;
+        self.wake.notify_waiters();
+        reply_rx.await.map_err(|_| anyhow!("Room is closed"))?
+    }
+
+    // Only the match loop receives commands, so it can only be taken once
+    pub fn take_commands(&self) -> Option<mpsc::Receiver<AdminRequest>> {
+        self.command_rx.lock().ok()?.take()
+    }
+
+    // Refuses votes unless a turn is running
+    pub fn ensure_accepting_votes(&self) -> Result<(), MoveError> {
+        if self.phase.load().accepts_votes() {
+            Ok(())
+        } else {
+            Err(MoveError::TurnClosed)
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    // Public rooms admit everyone, private rooms only those with the invite code
+    pub fn admits(&self, code: Option<&str>) -> bool {
+        match &self.invite_code {
+            Some(invite_code) => code.is_some_and(|code| code.eq_ignore_ascii_case(invite_code)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminCommand {
+    Pause,
+    Resume,
+    // Commits the current winning vote right away
+    Commit,
+    // Starts the match over with an empty board
+    Reset,
+    SkipTurn,
+    ForceMove { section: usize, cell: usize },
+    EndMatch { status: Status },
+    // Moves the current turn's deadline, negative values take time away
+    AdjustTimer { secs: i64 },
+    // Takes effect from the next turn
+    UpdateTiming { timing: MatchTiming },
+}
+
+pub type AdminRequest = (AdminCommand, oneshot::Sender<Result<()>>);
+
+// Short code without easily confused characters such as 0/O and 1/I
+pub fn generate_invite_code() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut bits = Uuid::new_v4().as_u128();
+    (0..6)
+        .map(|_| {
+            let c = ALPHABET[(bits % ALPHABET.len() as u128) as usize] as char;
+            bits /= ALPHABET.len() as u128;
+            c
+        })
+        .collect()
+}
+
+impl Drop for Room {
+    fn drop(&mut self) {
+        self.game
+            .snap_tx
+            .send_all(SnapshotResponse {
+                snap: [[0; 9]; 9],
+                your_team: None,
+                phase: VotePhase::Cell,
+                sections: None,
+                voter_count: 0,
+                revealed: None,
+            })
+            .ok();
+        self.teams_tx
+            .send(TeamsResponse {
+                x_team_size: 0,
+                o_team_size: 0,
+                is_captain: false,
+            })
+            .ok();
+        self.timer_tx
+            .send(TimerResponse {
+                start: Utc::now(),
+                stop: Utc::now(),
+                is_paused: true,
+                remaining_ms: 0,
+                banks: None,
+            })
+            .ok();
+        self.game
+            .match_tx
+            .send(MatchSchema {
+                id: Uuid::new_v4(),
+                board: Board::new(),
+                settings: MatchSettings::default(),
+                series_id: None,
+                starting_team: Team::default(),
+                version: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })
+            .ok();
+    }
+}
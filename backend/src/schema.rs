@@ -2,17 +2,25 @@ use std::{
     array,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Mutex,
     },
 };
 
-use anyhow::{bail, ensure, Error, Result};
+use anyhow::{anyhow, bail, ensure, Error, Result};
 use chrono::{DateTime, Utc};
+use crossbeam::atomic::AtomicCell;
+use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::Type;
+use tokio::sync::broadcast::{self, error::SendError};
 use uuid::Uuid;
 
-use crate::{model::MatchModel, AppState};
+use crate::{
+    error::MoveError,
+    model::{AuditEntryModel, MatchModel, QueuedMatchModel, SeriesModel},
+    phase::GamePhase,
+};
 
 const WINNING_SETS: [[usize; 3]; 8] = [
     [0, 1, 2],
@@ -25,6 +33,8 @@ const WINNING_SETS: [[usize; 3]; 8] = [
     [2, 4, 6],
 ];
 const DEFAULT_TEAM: Team = Team::X;
+// Longest series a room or the queue can be asked to play
+const MAX_BEST_OF: usize = 99;
 
 // #[derive(Clone, Serialize, Deserialize, Debug)]
 // #[serde(rename_all = "lowercase")]
@@ -35,6 +45,15 @@ const DEFAULT_TEAM: Team = Team::X;
 
 pub struct Snapshot {
     pub snap: [[AtomicUsize; 9]; 9],
+    // Section votes, only used during the section phase of a two-phase turn
+    pub sections: [AtomicUsize; 9],
+    // Connections that have voted during the current turn
+    pub voters: DashSet<Uuid>,
+    // Incremented every time the snapshot is reset
+    pub turn: AtomicUsize,
+    // Picks made by the voting team's captain in captain mode
+    pub captain_cell: AtomicCell<Option<(usize, usize)>>,
+    pub captain_section: AtomicCell<Option<usize>>,
 }
 
 impl Snapshot {
@@ -48,7 +67,14 @@ impl Snapshot {
             }
         }
 
-        Self { snap }
+        Self {
+            snap,
+            sections: Default::default(),
+            voters: DashSet::new(),
+            turn: AtomicUsize::new(0),
+            captain_cell: AtomicCell::new(None),
+            captain_section: AtomicCell::new(None),
+        }
     }
 
     pub fn load(&self) -> [[usize; 9]; 9] {
@@ -61,18 +87,51 @@ impl Snapshot {
         snap
     }
 
+    pub fn load_sections(&self) -> [usize; 9] {
+        array::from_fn(|i| self.sections[i].load(Ordering::Acquire))
+    }
+
     pub fn reset(&self) {
         for row in self.snap.iter() {
             for cell in row.iter() {
                 cell.store(0, Ordering::SeqCst);
             }
         }
+        for sec in self.sections.iter() {
+            sec.store(0, Ordering::SeqCst);
+        }
+        self.voters.clear();
+        self.captain_cell.store(None);
+        self.captain_section.store(None);
+        self.turn.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn turn(&self) -> usize {
+        self.turn.load(Ordering::Acquire)
     }
 
     pub fn increment(&self, row: usize, col: usize) -> usize {
         self.snap[row][col].fetch_add(1, Ordering::AcqRel)
     }
 
+    pub fn increment_section(&self, section: usize) -> usize {
+        self.sections[section].fetch_add(1, Ordering::AcqRel)
+    }
+
+    // Lets everyone vote again, e.g. when a two-phase turn moves on to the cell phase
+    pub fn reset_voters(&self) {
+        self.voters.clear();
+    }
+
+    // Returns false if the connection has already voted
+    pub fn record_voter(&self, id: Uuid) -> bool {
+        self.voters.insert(id)
+    }
+
+    pub fn has_voted(&self, id: &Uuid) -> bool {
+        self.voters.contains(id)
+    }
+
     pub fn find_max_indices(&self) -> Option<(usize, usize)> {
         let arr = self.load();
 
@@ -96,37 +155,79 @@ impl Snapshot {
         Some(max_indices)
     }
 
+    pub fn find_max_section(&self) -> Option<usize> {
+        let sections = self.load_sections();
+        let (section, &max) = sections
+            .iter()
+            .enumerate()
+            .max_by_key(|&(i, v)| (v, -(i as isize)))?;
+        (max > 0).then_some(section)
+    }
+
+    // The captain's pick wins over the crowd
+    pub fn winning_move(&self) -> Option<(usize, usize)> {
+        self.captain_cell.load().or_else(|| self.find_max_indices())
+    }
+
+    pub fn winning_section(&self) -> Option<usize> {
+        self.captain_section
+            .load()
+            .or_else(|| self.find_max_section())
+    }
+
     pub fn is_empty(&self) -> bool {
         self.load()
             .iter()
             .all(|row| row.iter().all(|&cell| cell == 0))
     }
+}
 
-    pub fn validate_move(
-        &self,
-        state: Arc<AppState>,
-        section: usize,
-        cell: usize,
-        team: Team,
-    ) -> Result<()> {
-        let curr_match = state.match_schema.load();
+// Votes cast by a single connection. In blind mode this is all a client gets
+// to see of the current turn.
+pub struct Ballot {
+    votes: Mutex<BallotVotes>,
+}
 
-        ensure!(section < 9 && cell < 9, "Invalid row or column index");
-        ensure!(
-            curr_match.board.current_team == team,
-            "Invalid team according to match state"
-        );
+#[derive(Clone, Copy, Default)]
+struct BallotVotes {
+    turn: usize,
+    snap: [[usize; 9]; 9],
+    sections: [usize; 9],
+}
 
-        let board_is_interactive = curr_match.board.is_interactive();
-        ensure!(board_is_interactive, "Board is not interactive");
+impl Ballot {
+    pub fn new() -> Self {
+        Self {
+            votes: Mutex::new(BallotVotes::default()),
+        }
+    }
 
-        let section_is_interactive = curr_match.board.data[section].is_interactive();
-        ensure!(section_is_interactive, "Section is not interactive");
+    // Votes from an earlier turn are discarded
+    fn current(&self, turn: usize) -> std::sync::MutexGuard<'_, BallotVotes> {
+        let mut votes = self.votes.lock().unwrap();
+        if votes.turn != turn {
+            *votes = BallotVotes {
+                turn,
+                ..Default::default()
+            };
+        }
+        votes
+    }
 
-        let cell_is_interactive = curr_match.board.data[section].data[cell].is_interactive();
-        ensure!(cell_is_interactive, "Cell is not interactive");
+    pub fn record(&self, turn: usize, section: usize, cell: usize) {
+        self.current(turn).snap[section][cell] += 1;
+    }
 
-        Ok(())
+    pub fn record_section(&self, turn: usize, section: usize) {
+        self.current(turn).sections[section] += 1;
+    }
+
+    pub fn load(&self, turn: usize) -> [[usize; 9]; 9] {
+        self.current(turn).snap
+    }
+
+    pub fn load_sections(&self, turn: usize) -> [usize; 9] {
+        self.current(turn).sections
     }
 }
 
@@ -187,7 +288,7 @@ impl GameStatusEvaluator for Section {
             return Status::Tied;
         }
 
-        return Status::Pending;
+        Status::Pending
     }
 }
 
@@ -219,12 +320,16 @@ impl GameStatusEvaluator for Board {
             return Status::Tied;
         }
 
-        return Status::Pending;
+        Status::Pending
     }
 }
 
 impl Board {
     pub fn new() -> Board {
+        Board::new_starting(Team::default())
+    }
+
+    pub fn new_starting(team: Team) -> Board {
         Board {
             data: array::from_fn(|_| Section {
                 data: array::from_fn(|_| Cell {
@@ -234,27 +339,136 @@ impl Board {
                 is_interactive: true,
             }),
             status: Status::Pending,
-            current_team: Team::default(),
+            current_team: team,
         }
     }
 
-    fn validate_move(&self, coord: (usize, usize), team: Team) -> Result<()> {
-        if team != self.current_team {
-            bail!("Not this team's turn");
+    // Number of moves played so far
+    pub fn ply(&self) -> usize {
+        self.data
+            .iter()
+            .flat_map(|sec| sec.data.iter())
+            .filter(|cell| !cell.is_interactive())
+            .count()
+    }
+
+    pub fn interactive_sections(&self) -> usize {
+        self.data.iter().filter(|sec| sec.is_interactive()).count()
+    }
+
+    // Picks any playable cell, used when a team runs out of time
+    pub fn random_move(&self) -> Option<(usize, usize)> {
+        let moves: Vec<(usize, usize)> = (0..9)
+            .flat_map(|sec| (0..9).map(move |cell| (sec, cell)))
+            .filter(|&(sec, cell)| self.validate_move((sec, cell), self.current_team).is_ok())
+            .collect();
+
+        match moves.len() {
+            0 => None,
+            len => Some(moves[(Uuid::new_v4().as_u128() % len as u128) as usize]),
         }
+    }
 
-        if !self.is_interactive() {
-            bail!("Board is not interactive");
+    // Ends the match with the current team losing
+    pub fn forfeited(&self) -> Self {
+        self.ended(self.current_team.toggle().into())
+    }
+
+    pub fn ended(&self, status: Status) -> Self {
+        let mut new_board = *self;
+        new_board.status = status;
+        for sec in new_board.data.iter_mut() {
+            sec.is_interactive = false;
         }
+        new_board
+    }
 
+    // Compact text form for logs, e.g. `x...o..../.../... o pending`.
+    // Sections are separated by `/`, followed by the team to move and the status.
+    pub fn notation(&self) -> String {
+        let symbol = |status: Status| match status {
+            Status::X => 'x',
+            Status::O => 'o',
+            Status::Tied => '-',
+            Status::Pending => '.',
+        };
+        let sections: Vec<String> = self
+            .data
+            .iter()
+            .map(|sec| sec.data.iter().map(|cell| symbol(cell.status)).collect())
+            .collect();
+        let status = match self.status {
+            Status::X => "x",
+            Status::O => "o",
+            Status::Tied => "tied",
+            Status::Pending => "pending",
+        };
+
+        format!(
+            "{} {} {}",
+            sections.join("/"),
+            symbol(self.current_team.into()),
+            status
+        )
+    }
+
+    // Passes the turn to the other team without a move
+    pub fn skipped(&self) -> Self {
+        Self {
+            current_team: self.current_team.toggle(),
+            ..*self
+        }
+    }
+
+    // Restricts play to a single section, used once a two-phase turn picks one
+    pub fn with_locked_section(&self, section: usize) -> Result<Self> {
+        ensure!(section < 9, "Invalid section index");
+        ensure!(
+            self.data[section].is_interactive(),
+            "Section is not interactive"
+        );
+
+        let mut new_board = *self;
+        for (i, sec) in new_board.data.iter_mut().enumerate() {
+            sec.is_interactive = i == section;
+        }
+
+        Ok(new_board)
+    }
+
+    pub fn validate_move(&self, coord: (usize, usize), team: Team) -> Result<(), MoveError> {
+        if coord.0 >= 9 || coord.1 >= 9 {
+            return Err(MoveError::OutOfRange);
+        }
+        if team != self.current_team {
+            return Err(MoveError::NotYourTurn);
+        }
+        if !self.is_interactive() {
+            return Err(MoveError::MatchOver);
+        }
         let sec = &self.data[coord.0];
         if !sec.is_interactive() {
-            bail!("Section is not interactive");
+            return Err(MoveError::SectionClosed);
+        }
+        if !sec.data[coord.1].is_interactive() {
+            return Err(MoveError::CellTaken);
         }
 
-        let cell = &sec.data[coord.1];
-        if !cell.is_interactive() {
-            bail!("Cell is not interactive");
+        Ok(())
+    }
+
+    pub fn validate_section(&self, section: usize, team: Team) -> Result<(), MoveError> {
+        if section >= 9 {
+            return Err(MoveError::OutOfRange);
+        }
+        if team != self.current_team {
+            return Err(MoveError::NotYourTurn);
+        }
+        if !self.is_interactive() {
+            return Err(MoveError::MatchOver);
+        }
+        if !self.data[section].is_interactive() {
+            return Err(MoveError::SectionClosed);
         }
 
         Ok(())
@@ -267,7 +481,7 @@ impl Board {
         let sec_index = coord.0;
         let cell_index = coord.1;
 
-        let mut new_board = self.clone();
+        let mut new_board = *self;
         let sec = &mut new_board.data[sec_index];
         let cell = &mut sec.data[cell_index];
 
@@ -301,6 +515,115 @@ pub struct Pagination {
     pub limit: Option<usize>,
 }
 
+pub struct CreateAuditEntrySchema {
+    pub actor: String,
+    pub action: String,
+    pub match_id: Option<Uuid>,
+    pub board_before: Option<String>,
+    pub board_after: Option<String>,
+    pub details: Value,
+}
+
+impl CreateAuditEntrySchema {
+    pub fn new(actor: &str, action: &str, match_id: Uuid, before: &Board, after: &Board) -> Self {
+        Self {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            match_id: Some(match_id),
+            board_before: Some(before.notation()),
+            board_after: Some(after.notation()),
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    // Actions that don't touch a board, such as changes to the queue
+    pub fn without_match(actor: &str, action: &str) -> Self {
+        Self {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            match_id: None,
+            board_before: None,
+            board_after: None,
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    pub fn with_details(self, details: Value) -> Self {
+        Self { details, ..self }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditEntrySchema {
+    pub id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub match_id: Option<Uuid>,
+    pub board_before: Option<String>,
+    pub board_after: Option<String>,
+    pub details: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&AuditEntryModel> for AuditEntrySchema {
+    fn from(model: &AuditEntryModel) -> Self {
+        Self {
+            id: model.id,
+            actor: model.actor.clone(),
+            action: model.action.clone(),
+            match_id: model.match_id,
+            board_before: model.board_before.clone(),
+            board_after: model.board_after.clone(),
+            details: model.details.0.clone(),
+            created_at: model.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    pub match_id: Option<Uuid>,
+    pub actor: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EndMatchSchema {
+    pub status: Status,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdjustTimerSchema {
+    pub secs: i64,
+}
+
+impl AdjustTimerSchema {
+    // A day either way is more than any turn needs
+    const MAX_SECS: i64 = 86_400;
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.secs.abs() <= Self::MAX_SECS,
+            "secs must be between -{} and {}",
+            Self::MAX_SECS,
+            Self::MAX_SECS
+        );
+        Ok(())
+    }
+}
+
+// A vote cast over HTTP. The token comes from the welcome of a live WebSocket connection,
+// whose team and votes it shares.
+#[derive(Deserialize, Debug)]
+pub struct VoteQuery {
+    pub section: usize,
+    pub cell: usize,
+    pub voter_token: Uuid,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Coords {
     pub section: usize,
@@ -319,10 +642,7 @@ pub enum Status {
 
 impl Status {
     pub fn is_complete(&self) -> bool {
-        match self {
-            Status::X | Status::O | Status::Tied => true,
-            _ => false,
-        }
+        matches!(self, Status::X | Status::O | Status::Tied)
     }
 }
 
@@ -389,10 +709,277 @@ impl Team {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CreateMatchSchema {
-    pub state: Status,
-    pub board: Board,
+// Rules that end a turn before its timer runs out
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EarlyCloseRules {
+    // Close once every connected member of the voting team has voted
+    pub all_voted: bool,
+    // Close once a single cell holds at least this percentage of the votes
+    pub supermajority: Option<u8>,
+    // Votes required before the supermajority rule applies
+    pub min_votes: usize,
+}
+
+impl EarlyCloseRules {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(percent) = self.supermajority {
+            ensure!(
+                (51..=100).contains(&percent),
+                "supermajority must be between 51 and 100 percent"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_supermajority(&self, tally: &[usize]) -> bool {
+        let Some(percent) = self.supermajority else {
+            return false;
+        };
+
+        let total: usize = tally.iter().sum();
+        if total < self.min_votes {
+            return false;
+        }
+
+        let max = tally.iter().copied().max().unwrap_or(0);
+        max * 100 >= total * percent as usize
+    }
+}
+
+// Which team moves first in the next match
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StartingTeamPolicy {
+    #[default]
+    Alternate,
+    Random,
+    LoserStarts,
+}
+
+impl StartingTeamPolicy {
+    pub fn pick(&self, previous: Option<&MatchSchema>) -> Team {
+        let alternate = previous.map_or(Team::default(), |m| m.starting_team.toggle());
+        match self {
+            StartingTeamPolicy::Alternate => alternate,
+            StartingTeamPolicy::Random => match Uuid::new_v4().as_u128() % 2 {
+                0 => Team::X,
+                _ => Team::O,
+            },
+            // Ties fall back to alternating
+            StartingTeamPolicy::LoserStarts => match previous.map(|m| m.board.status) {
+                Some(Status::X) => Team::O,
+                Some(Status::O) => Team::X,
+                _ => alternate,
+            },
+        }
+    }
+}
+
+// What happens to a team whose time bank runs out before it has voted
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagPolicy {
+    #[default]
+    Lose,
+    RandomMove,
+}
+
+// Chess-clock time control. Each team's bank only runs during its own turns.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct TimeControl {
+    pub bank_secs: u32,
+    // Added to a team's bank after each of its moves
+    #[serde(default)]
+    pub increment_secs: u32,
+    #[serde(default)]
+    pub on_flag: FlagPolicy,
+}
+
+// Milliseconds left in each team's bank
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct TimeBanks {
+    pub x_ms: i64,
+    pub o_ms: i64,
+}
+
+impl TimeBanks {
+    pub fn new(time_control: &TimeControl) -> Self {
+        let ms = time_control.bank_secs as i64 * 1000;
+        Self { x_ms: ms, o_ms: ms }
+    }
+
+    pub fn get(&self, team: Team) -> i64 {
+        match team {
+            Team::X => self.x_ms,
+            Team::O => self.o_ms,
+        }
+    }
+
+    pub fn with(&self, team: Team, ms: i64) -> Self {
+        let ms = ms.max(0);
+        match team {
+            Team::X => Self { x_ms: ms, ..*self },
+            Team::O => Self { o_ms: ms, ..*self },
+        }
+    }
+}
+
+// Turn length and other thresholds of a match
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MatchTiming {
+    pub turn_secs: u32,
+    // Break between a finished match and the next one
+    pub intermission_secs: u32,
+    // How often to check again when the turn is over but nobody has voted
+    pub overtime_poll_secs: u32,
+    pub min_players_per_team: usize,
+}
+
+impl MatchTiming {
+    // A zero turn or poll would make the match loop spin
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (1..=3600).contains(&self.turn_secs),
+            "turn_secs must be between 1 and 3600"
+        );
+        ensure!(
+            self.intermission_secs <= 3600,
+            "intermission_secs must be at most 3600"
+        );
+        ensure!(
+            (1..=600).contains(&self.overtime_poll_secs),
+            "overtime_poll_secs must be between 1 and 600"
+        );
+        ensure!(
+            (1..=1000).contains(&self.min_players_per_team),
+            "min_players_per_team must be between 1 and 1000"
+        );
+        Ok(())
+    }
+}
+
+impl Default for MatchTiming {
+    fn default() -> Self {
+        Self {
+            turn_secs: 20,
+            intermission_secs: 30,
+            overtime_poll_secs: 4,
+            min_players_per_team: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MatchSettings {
+    pub early_close: EarlyCloseRules,
+    // Clients only see their own votes until the move is committed
+    pub blind: bool,
+    // Live tallies are only sent to the team that is voting
+    pub fog: bool,
+    // When any section may be played, vote on the section before the cell
+    pub two_phase: bool,
+    // Each team's captain decides the move, the crowd only if the captain doesn't act in time
+    pub captain: bool,
+    pub starting_team: StartingTeamPolicy,
+    // Replaces the fixed turn length with per-team time banks
+    pub time_control: Option<TimeControl>,
+    // Server defaults are filled in when the match is created
+    pub timing: Option<MatchTiming>,
+}
+
+impl MatchSettings {
+    pub fn validate(&self) -> Result<()> {
+        self.early_close.validate()?;
+        if let Some(timing) = self.timing {
+            timing.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn with_default_timing(mut self, defaults: MatchTiming) -> Self {
+        self.timing.get_or_insert(defaults);
+        self
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CreateRoomSchema {
+    #[serde(flatten)]
+    pub settings: MatchSettings,
+    #[serde(default)]
+    pub private: bool,
+    // Play a best-of-N series instead of standalone matches
+    pub best_of: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreateRoomResponse {
+    #[serde(flatten)]
+    pub match_schema: MatchSchema,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
+}
+
+// Upcoming match for the public room
+#[derive(Serialize, Debug)]
+pub struct QueuedMatchSchema {
+    pub id: Uuid,
+    pub position: usize,
+    pub settings: MatchSettings,
+    pub best_of: Option<usize>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TryFrom<&QueuedMatchModel> for QueuedMatchSchema {
+    type Error = Error;
+    fn try_from(q: &QueuedMatchModel) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: q.id,
+            position: q.position as usize,
+            settings: serde_json::from_value::<MatchSettings>(q.settings.0.clone())?,
+            best_of: q.best_of.map(|b| b as usize),
+            starts_at: q.starts_at,
+            created_at: q.created_at,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CreateQueuedMatchSchema {
+    #[serde(flatten)]
+    pub settings: MatchSettings,
+    pub best_of: Option<usize>,
+    // Not started before this time, even if it is first in the queue
+    pub starts_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReorderQueueSchema {
+    pub ids: Vec<Uuid>,
+}
+
+// Lobby listing of a live room
+#[derive(Serialize, Debug)]
+pub struct RoomResponse {
+    pub id: Uuid,
+    pub match_id: Uuid,
+    pub status: Status,
+    pub turn: usize,
+    pub current_team: Team,
+    pub x_team_size: usize,
+    pub o_team_size: usize,
+    pub time_remaining_ms: i64,
+    #[serde(flatten)]
+    pub phase: GamePhase,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct InviteQuery {
+    pub code: Option<String>,
 }
 
 // For json response
@@ -400,6 +987,11 @@ pub struct CreateMatchSchema {
 pub struct MatchSchema {
     pub id: Uuid,
     pub board: Board,
+    pub settings: MatchSettings,
+    pub series_id: Option<Uuid>,
+    pub starting_team: Team,
+    // Bumped on every board update, updates must name the version they were based on
+    pub version: u32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -410,30 +1002,160 @@ impl TryFrom<&MatchModel> for MatchSchema {
         Ok(Self {
             id: m.id,
             board: serde_json::from_value::<Board>(m.board.0.clone())?,
+            settings: serde_json::from_value::<MatchSettings>(m.settings.0.clone())?,
+            series_id: m.series_id,
+            starting_team: Team::try_from(m.starting_team).map_err(|e| anyhow!(e))?,
+            version: m.version as u32,
             created_at: m.created_at,
             updated_at: m.updated_at,
         })
     }
 }
 
+// Standings of a best-of-N series
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct SeriesSchema {
+    pub id: Uuid,
+    // Winning team once either team reaches the target, or the team with more wins once
+    // every game has been played. Tied if they are still level.
+    pub status: Status,
+    pub best_of: usize,
+    pub target_wins: usize,
+    pub x_wins: usize,
+    pub o_wins: usize,
+    pub ties: usize,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SeriesSchema {
+    // A best-of-N series is won by the first team to take a majority of the games
+    pub fn target_wins(best_of: usize) -> Result<usize> {
+        Self::validate_best_of(Some(best_of))?;
+        Ok(best_of / 2 + 1)
+    }
+
+    pub fn validate_best_of(best_of: Option<usize>) -> Result<()> {
+        if let Some(best_of) = best_of {
+            ensure!(
+                (1..=MAX_BEST_OF).contains(&best_of),
+                "best_of must be between 1 and {}",
+                MAX_BEST_OF
+            );
+        }
+        Ok(())
+    }
+
+    pub fn games_played(&self) -> usize {
+        self.x_wins + self.o_wins + self.ties
+    }
+
+    // Teams take turns starting, beginning with the default team
+    pub fn next_starting_team(&self) -> Team {
+        match self.games_played() % 2 {
+            0 => Team::default(),
+            _ => Team::default().toggle(),
+        }
+    }
+
+    pub fn with_result(&self, result: Status) -> Self {
+        let mut series = *self;
+        match result {
+            Status::X => series.x_wins += 1,
+            Status::O => series.o_wins += 1,
+            Status::Tied => series.ties += 1,
+            Status::Pending => return series,
+        }
+
+        if series.x_wins >= series.target_wins {
+            series.status = Status::X;
+        } else if series.o_wins >= series.target_wins {
+            series.status = Status::O;
+        } else if series.games_played() >= series.best_of {
+            series.status = if series.x_wins > series.o_wins {
+                Status::X
+            } else if series.o_wins > series.x_wins {
+                Status::O
+            } else {
+                Status::Tied
+            };
+        }
+
+        series
+    }
+}
+
+impl From<&SeriesModel> for SeriesSchema {
+    fn from(s: &SeriesModel) -> Self {
+        Self {
+            id: s.id,
+            status: s.state,
+            best_of: s.best_of as usize,
+            target_wins: s.target_wins as usize,
+            x_wins: s.x_wins as usize,
+            o_wins: s.o_wins as usize,
+            ties: s.ties as usize,
+            created_at: s.created_at,
+            updated_at: s.updated_at,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct IncrementRequest {
     pub section: usize,
     pub cell: usize,
 }
 
+// Vote for a section during the first phase of a two-phase turn
+#[derive(Debug, Deserialize)]
+pub struct SectionRequest {
+    pub section: usize,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VotePhase {
+    Section,
+    Cell,
+}
+
 #[derive(Clone, Serialize, Debug)]
 pub struct SnapshotResponse {
     // Team is not none only on new connection. This tells
     // the client what team they are on
     pub your_team: Option<Team>,
     pub snap: [[usize; 9]; 9],
+    pub phase: VotePhase,
+    // Section tally, only present during the section phase of a two-phase turn
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sections: Option<[usize; 9]>,
+    // Number of connections that have voted this turn
+    pub voter_count: usize,
+    // Full tally of the previous turn, sent once its move is committed in blind mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revealed: Option<[[usize; 9]; 9]>,
+}
+
+impl SnapshotResponse {
+    pub fn new(snapshot: &Snapshot, phase: VotePhase) -> Self {
+        Self {
+            your_team: None,
+            snap: snapshot.load(),
+            phase,
+            sections: (phase == VotePhase::Section).then(|| snapshot.load_sections()),
+            voter_count: snapshot.voters.len(),
+            revealed: None,
+        }
+    }
 }
 
 #[derive(Clone, Serialize)]
 pub struct TeamsResponse {
     pub x_team_size: usize,
     pub o_team_size: usize,
+    // Set per connection in captain mode
+    pub is_captain: bool,
 }
 
 #[derive(Clone, Serialize)]
@@ -441,11 +1163,14 @@ pub struct TimerResponse {
     pub start: DateTime<Utc>,
     pub stop: DateTime<Utc>,
     pub is_paused: bool,
+    // Time left on the clock when this was sent. Does not count down while paused.
+    pub remaining_ms: i64,
+    // Banks as of the start of the turn. The current team's bank runs out at `stop`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banks: Option<TimeBanks>,
 }
 
-use dashmap::DashSet;
-
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct TeamConnection {
     pub team: Team,
     pub id: Uuid, // Unique identifier for each connection
@@ -455,6 +1180,10 @@ pub struct TeamConnection {
 pub struct Teams {
     pub team_x: DashSet<Uuid>,
     pub team_o: DashSet<Uuid>,
+    captain_x: AtomicCell<Option<Uuid>>,
+    captain_o: AtomicCell<Option<Uuid>>,
+    // Tokens handed to connected clients so they can also vote over HTTP
+    voters: DashMap<Uuid, TeamConnection>,
 }
 
 impl Teams {
@@ -462,6 +1191,9 @@ impl Teams {
         Self {
             team_x: DashSet::new(),
             team_o: DashSet::new(),
+            captain_x: AtomicCell::new(None),
+            captain_o: AtomicCell::new(None),
+            voters: DashMap::new(),
         }
     }
 
@@ -472,7 +1204,7 @@ impl Teams {
         let o_count = self.team_o.len();
 
         // Balance the teams on new connection
-        if x_count <= o_count {
+        let connection = if x_count <= o_count {
             // Default team is X
             self.team_x.insert(id);
             TeamConnection {
@@ -485,7 +1217,25 @@ impl Teams {
                 team: Team::default().toggle(),
                 id,
             }
-        }
+        };
+
+        // The first member of a team becomes its captain
+        let _ = self
+            .captain_slot(connection.team)
+            .compare_exchange(None, Some(id));
+
+        connection
+    }
+
+    // The token stays valid until the connection is removed
+    pub fn issue_voter_token(&self, connection: &TeamConnection) -> Uuid {
+        let token = Uuid::new_v4();
+        self.voters.insert(token, *connection);
+        token
+    }
+
+    pub fn voter(&self, token: Uuid) -> Option<TeamConnection> {
+        self.voters.get(&token).map(|voter| *voter)
     }
 
     pub fn remove_connection(&self, connection: &TeamConnection) {
@@ -493,6 +1243,36 @@ impl Teams {
             Team::X => self.team_x.remove(&connection.id),
             Team::O => self.team_o.remove(&connection.id),
         };
+        self.voters.retain(|_, voter| voter.id != connection.id);
+
+        // Hand the captaincy to another member if the captain left
+        let slot = self.captain_slot(connection.team);
+        if slot.load() == Some(connection.id) {
+            let next = self.members(connection.team).iter().next().map(|id| *id);
+            slot.store(next);
+        }
+    }
+
+    fn captain_slot(&self, team: Team) -> &AtomicCell<Option<Uuid>> {
+        match team {
+            Team::X => &self.captain_x,
+            Team::O => &self.captain_o,
+        }
+    }
+
+    pub fn captain(&self, team: Team) -> Option<Uuid> {
+        self.captain_slot(team).load()
+    }
+
+    pub fn is_captain(&self, connection: &TeamConnection) -> bool {
+        self.captain(connection.team) == Some(connection.id)
+    }
+
+    pub fn members(&self, team: Team) -> &DashSet<Uuid> {
+        match team {
+            Team::X => &self.team_x,
+            Team::O => &self.team_o,
+        }
     }
 
     pub fn team_lens(&self) -> (usize, usize) {
@@ -501,3 +1281,42 @@ impl Teams {
         (x_count, o_count)
     }
 }
+
+// A broadcast channel per team so messages can be kept from the opposing team
+pub struct TeamBroadcast<T> {
+    team_x: broadcast::Sender<T>,
+    team_o: broadcast::Sender<T>,
+}
+
+impl<T: Clone> TeamBroadcast<T> {
+    pub fn new(capacity: usize) -> Self {
+        let (team_x, _) = broadcast::channel(capacity);
+        let (team_o, _) = broadcast::channel(capacity);
+        Self { team_x, team_o }
+    }
+
+    pub fn subscribe(&self, team: Team) -> broadcast::Receiver<T> {
+        match team {
+            Team::X => self.team_x.subscribe(),
+            Team::O => self.team_o.subscribe(),
+        }
+    }
+
+    pub fn send(&self, team: Team, msg: T) -> Result<usize, SendError<T>> {
+        match team {
+            Team::X => self.team_x.send(msg),
+            Team::O => self.team_o.send(msg),
+        }
+    }
+
+    // Only fails if neither team has any receivers
+    pub fn send_all(&self, msg: T) -> Result<usize, SendError<T>> {
+        let x = self.team_x.send(msg.clone());
+        let o = self.team_o.send(msg);
+        match (x, o) {
+            (Ok(x), Ok(o)) => Ok(x + o),
+            (Ok(n), Err(_)) | (Err(_), Ok(n)) => Ok(n),
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+}
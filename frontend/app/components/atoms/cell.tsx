@@ -2,7 +2,7 @@ import { Button } from "@/components/ui/button";
 import { cn } from "@/lib/utils";
 import { wsService } from "@/lib/ws";
 import { MatchContext } from "@/routes/game";
-import { Status, type Cell } from "@/types";
+import { Status, type Cell, type ClientMessage } from "@/types";
 import { Circle, X } from "lucide-react";
 import { useContext, useEffect, useState } from "react";
 
@@ -32,9 +32,10 @@ export function Cell({
     if (location.length != 2) return;
     wsService.send(
       JSON.stringify({
+        type: "vote",
         section: location[0],
         cell: location[1],
-      })
+      } satisfies ClientMessage)
     );
   };
 
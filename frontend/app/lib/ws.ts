@@ -1,4 +1,6 @@
 // websocketService.ts
+import { type ClientMessage, PROTOCOL_VERSION } from "@/types";
+
 type MessageHandler = (data: string) => void;
 
 class WebSocketService {
@@ -38,6 +40,13 @@ class WebSocketService {
     this.ws.onopen = () => {
       console.log("WebSocket connected");
       this.isConnecting = false;
+      this.ws?.send(
+        JSON.stringify({
+          type: "hello",
+          version: PROTOCOL_VERSION,
+          capabilities: ["delta"],
+        } satisfies ClientMessage)
+      );
       if (this.reconnectTimeout) {
         clearTimeout(this.reconnectTimeout);
         this.reconnectTimeout = null;
@@ -16,11 +16,9 @@ import { cn } from "@/lib/utils";
 import { wsService } from "@/lib/ws";
 import {
   type Board as BoardT,
-  isMatch,
-  isSnapshotResponse,
-  isTeamsResponse,
-  isTimerResponse,
+  ClientMessage,
   Match,
+  ServerMessage,
   Status,
   Team,
 } from "@/types";
@@ -30,6 +28,7 @@ import {
   useCallback,
   useEffect,
   useMemo,
+  useRef,
   useState,
 } from "react";
 
@@ -63,24 +62,71 @@ export default function Index({ loaderData }: Route.ComponentProps) {
   const [stopTime, setStopTime] = useState<Date | null>(null);
   const [timeRemaining, setTimeRemaining] = useState<number | null>(null);
   const [isPaused, setIsPaused] = useState<boolean>(false);
+  const [remainingMs, setRemainingMs] = useState<number>(0);
+  // Sequence number of the last keyframe or delta applied to the snapshot
+  const snapshotSeq = useRef<number | null>(null);
 
   const messageHandler = useCallback((rawData: string) => {
     try {
-      const data = JSON.parse(rawData);
-      if (isSnapshotResponse(data)) {
-        setSnapshot(data.snap);
-        // Only set team if it is not null
-        if (data.your_team != null) setMyTeam(data.your_team);
-      } else if (isTimerResponse(data)) {
-        const start = new Date(data.start);
-        const stop = new Date(data.stop);
-        setStartTime(start);
-        setStopTime(stop);
-        setIsPaused(data.is_paused);
-      } else if (isTeamsResponse(data)) {
-        setTeamSize([data.x_team_size, data.o_team_size]);
-      } else if (isMatch(data)) {
-        setMatch(data);
+      const data = JSON.parse(rawData) as ServerMessage;
+      switch (data.type) {
+        case "welcome":
+          snapshotSeq.current = null;
+          setMyTeam(data.your_team);
+          setMatch(data.match);
+          setSnapshot(data.snapshot.snap);
+          setStartTime(new Date(data.timer.start));
+          setStopTime(new Date(data.timer.stop));
+          setIsPaused(data.timer.is_paused);
+          setRemainingMs(data.timer.remaining_ms);
+          setTeamSize([data.teams.x_team_size, data.teams.o_team_size]);
+          break;
+        case "snapshot":
+          setSnapshot(data.snap);
+          // Only set team if it is not null
+          if (data.your_team != null) setMyTeam(data.your_team);
+          break;
+        case "keyframe":
+          snapshotSeq.current = data.seq;
+          setSnapshot(data.snapshot.snap);
+          break;
+        case "snapshot_delta":
+          // A missed delta leaves the tally wrong, so start over from a keyframe
+          if (
+            snapshotSeq.current == null ||
+            data.seq !== snapshotSeq.current + 1
+          ) {
+            snapshotSeq.current = null;
+            wsService.send(
+              JSON.stringify({ type: "resync" } satisfies ClientMessage)
+            );
+            break;
+          }
+          snapshotSeq.current = data.seq;
+          setSnapshot((prev) => {
+            const next = (prev ?? []).map((section) => [...section]);
+            for (const { section, cell, count } of data.changes) {
+              next[section][cell] = count;
+            }
+            return next;
+          });
+          break;
+        case "timer":
+          setStartTime(new Date(data.start));
+          setStopTime(new Date(data.stop));
+          setIsPaused(data.is_paused);
+          setRemainingMs(data.remaining_ms);
+          break;
+        case "teams":
+          setTeamSize([data.x_team_size, data.o_team_size]);
+          break;
+        case "match":
+          setMatch(data);
+          break;
+        case "rejected":
+        case "error":
+          console.warn(data.message);
+          break;
       }
     } catch (e) {
       console.error(e);
@@ -97,17 +143,25 @@ export default function Index({ loaderData }: Route.ComponentProps) {
     };
   }, []);
 
-  // When time is set, calculate the time remaining and start count down
+  // When time is set, calculate the time remaining and start count down.
+  // A paused clock stays where the server froze it.
   useEffect(() => {
     if (startTime != null && stopTime != null) {
       let initialDiff = startTime.getTime() - stopTime.getTime();
+      if (isPaused) {
+        const total = stopTime.getTime() - startTime.getTime();
+        setTimeRemaining(
+          total > 0 ? clamp(Math.floor((remainingMs / total) * 100), 0, 100) : 0
+        );
+        return;
+      }
       const interval = setInterval(() => {
         const diff = new Date().getTime() - stopTime.getTime();
         setTimeRemaining(clamp(Math.floor((diff / initialDiff) * 100), 0, 100));
       }, 500);
       return () => clearInterval(interval);
     }
-  }, [startTime, stopTime]);
+  }, [startTime, stopTime, isPaused, remainingMs]);
 
   const value = useMemo(() => {
     return {
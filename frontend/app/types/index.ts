@@ -30,9 +30,59 @@ export type Board = {
   current_team: Team;
 };
 
+export type EarlyCloseRules = {
+  all_voted: boolean;
+  supermajority: number | null; // 51 to 100 percent
+  min_votes: number; // votes needed before the supermajority rule applies
+};
+
+export enum StartingTeamPolicy {
+  Alternate = "alternate",
+  Random = "random",
+  LoserStarts = "loser_starts",
+}
+
+export enum FlagPolicy {
+  Lose = "lose",
+  RandomMove = "random_move",
+}
+
+export type TimeControl = {
+  bank_secs: number;
+  increment_secs: number;
+  on_flag: FlagPolicy;
+};
+
+export type TimeBanks = {
+  x_ms: number;
+  o_ms: number;
+};
+
+export type MatchTiming = {
+  turn_secs: number;
+  intermission_secs: number;
+  overtime_poll_secs: number;
+  min_players_per_team: number;
+};
+
+export type MatchSettings = {
+  early_close: EarlyCloseRules;
+  blind: boolean;
+  fog: boolean;
+  two_phase: boolean;
+  captain: boolean;
+  starting_team: StartingTeamPolicy;
+  time_control: TimeControl | null;
+  timing: MatchTiming | null;
+};
+
 export type Match = {
   id: string;
   board: Board;
+  settings: MatchSettings;
+  series_id: string | null;
+  starting_team: Team;
+  version: number;
   created_at: string;
   updated_at: string;
 };
@@ -48,9 +98,18 @@ export function isMatch(data: any): data is Match {
   );
 }
 
+export enum VotePhase {
+  Section = "section",
+  Cell = "cell",
+}
+
 export type SnapshotResponse = {
   your_team: Team | null;
   snap: number[][];
+  phase: VotePhase;
+  sections?: number[];
+  voter_count: number;
+  revealed?: number[][];
 };
 
 export function isSnapshotResponse(data: any): data is SnapshotResponse {
@@ -65,6 +124,7 @@ export function isSnapshotResponse(data: any): data is SnapshotResponse {
 export type TeamsResponse = {
   x_team_size: number;
   o_team_size: number;
+  is_captain: boolean;
 };
 
 export function isTeamsResponse(data: any): data is TeamsResponse {
@@ -80,6 +140,8 @@ export type TimerResponse = {
   start: string; // will be utc datetime
   stop: string; // will be utc datetime
   is_paused: boolean;
+  remaining_ms: number; // frozen while paused
+  banks?: TimeBanks;
 };
 
 export function isTimerResponse(data: any): data is TimerResponse {
@@ -91,3 +153,104 @@ export function isTimerResponse(data: any): data is TimerResponse {
     "is_paused" in data
   );
 }
+
+export type Series = {
+  id: string;
+  status: Status;
+  best_of: number;
+  target_wins: number;
+  x_wins: number;
+  o_wins: number;
+  ties: number;
+  created_at: string;
+  updated_at: string;
+};
+
+export function isSeries(data: any): data is Series {
+  return (
+    typeof data === "object" &&
+    data !== null &&
+    "target_wins" in data &&
+    "x_wins" in data &&
+    "o_wins" in data
+  );
+}
+
+export type GamePhase =
+  | { game_phase: "waiting_for_players"; paused_turn_ms: number | null }
+  | { game_phase: "paused"; paused_turn_ms: number | null }
+  | { game_phase: "voting"; deadline: string } // will be utc datetime
+  | { game_phase: "resolving" }
+  | { game_phase: "finished" }
+  | { game_phase: "intermission"; until: string }; // will be utc datetime
+
+export function isGamePhase(data: any): data is GamePhase {
+  return typeof data === "object" && data !== null && "game_phase" in data;
+}
+
+export enum MoveError {
+  OutOfRange = "out_of_range",
+  NotYourTurn = "not_your_turn",
+  MatchOver = "match_over",
+  SectionClosed = "section_closed",
+  CellTaken = "cell_taken",
+  VotingOnSection = "voting_on_section",
+  VotingOnCell = "voting_on_cell",
+  TurnClosed = "turn_closed",
+  AlreadyVoted = "already_voted",
+}
+
+export type ErrorResponse = {
+  code: MoveError | string;
+  message: string;
+};
+
+// Announced in the hello each connection opens with
+export const PROTOCOL_VERSION = 2;
+
+export type Welcome = {
+  version: number;
+  capabilities: string[];
+  your_team: Team;
+  voter_token: string;
+  match: Match;
+  snapshot: SnapshotResponse;
+  timer: TimerResponse;
+  phase: GamePhase;
+  teams: TeamsResponse;
+  series: Series | null;
+};
+
+export type CellChange = {
+  section: number;
+  cell: number;
+  count: number;
+};
+
+// Every websocket message from the server is tagged with its type
+export type ServerMessage =
+  | ({ type: "snapshot" } & SnapshotResponse)
+  | ({ type: "match" } & Match)
+  | ({ type: "teams" } & TeamsResponse)
+  | ({ type: "timer" } & TimerResponse)
+  | { type: "phase"; phase: GamePhase }
+  | ({ type: "series" } & Series)
+  // Replies to this client's own messages
+  | { type: "ack"; turn: number; section: number; cell?: number }
+  | { type: "rejected"; code: MoveError; message: string }
+  | ({ type: "error" } & ErrorResponse)
+  | ({ type: "welcome" } & Welcome)
+  // Sent instead of "snapshot" when the "delta" capability was agreed
+  | { type: "keyframe"; seq: number; snapshot: SnapshotResponse }
+  | {
+      type: "snapshot_delta";
+      seq: number;
+      changes: CellChange[];
+      voter_count: number;
+    };
+
+export type ClientMessage =
+  | { type: "hello"; version: number; capabilities: string[] }
+  | { type: "vote"; section: number; cell: number }
+  | { type: "section_vote"; section: number }
+  | { type: "resync" };